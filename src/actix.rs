@@ -0,0 +1,188 @@
+use std::future::{ready, Ready};
+
+use actix_web::{
+    body::EitherBody,
+    dev::Payload,
+    error::ErrorBadRequest,
+    http::header::{HeaderName, HeaderValue},
+    FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use http::{request::Parts, HeaderMap, Method, Request, Uri};
+
+use crate::headers;
+use crate::{StrictUnpoly, Unpoly, UpResponder};
+
+/// Convert the method, URI and headers of an actix request to `http::request::Parts`
+///
+/// actix-web uses version 0.2 of the `http` crate, so the values are converted via their byte representation.
+fn request_parts(request: &HttpRequest) -> Parts {
+    let (mut parts, _) = Request::new(()).into_parts();
+    parts.method = Method::from_bytes(request.method().as_str().as_bytes()).unwrap_or_default();
+    parts.uri = request.uri().to_string().parse::<Uri>().unwrap_or_default();
+    let mut headers = HeaderMap::new();
+    for (name, value) in request.headers() {
+        if let (Ok(name), Ok(value)) = (
+            http::HeaderName::from_bytes(name.as_str().as_bytes()),
+            http::HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    parts.headers = headers;
+    parts
+}
+
+impl FromRequest for Unpoly {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(Unpoly::from_request_parts(&request_parts(request))))
+    }
+}
+
+impl FromRequest for StrictUnpoly {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(
+            Unpoly::try_from_request_parts(&request_parts(request))
+                .map(StrictUnpoly)
+                .map_err(|err| ErrorBadRequest(err.to_string())),
+        )
+    }
+}
+
+/// Merge the Unpoly headers into an actix response, like `headers::merge_headers`
+fn merge_headers<B>(response: &mut HttpResponse<B>, unpoly_headers: HeaderMap) {
+    let headers = response.headers_mut();
+    for (name, value) in unpoly_headers.iter() {
+        let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_str().as_bytes()),
+            HeaderValue::from_bytes(value.as_bytes()),
+        ) else {
+            continue;
+        };
+        if name.as_str().eq_ignore_ascii_case(headers::VARY) {
+            let merged = headers::merge_vary(
+                headers
+                    .get_all(&name)
+                    .chain([&value])
+                    .filter_map(|value| value.to_str().ok()),
+            );
+            if let Ok(merged) = HeaderValue::from_str(&merged) {
+                headers.insert(name, merged);
+            }
+        } else if name.as_str().eq_ignore_ascii_case(headers::SET_COOKIE) {
+            headers.append(name, value);
+        } else {
+            headers.insert(name, value);
+        }
+    }
+}
+
+impl<R: Responder> Responder for UpResponder<R> {
+    type Body = EitherBody<R::Body>;
+
+    fn respond_to(self, request: &HttpRequest) -> HttpResponse<Self::Body> {
+        match self.unpoly.get_headers() {
+            Ok(unpoly_headers) => {
+                let mut response = self.responder.respond_to(request);
+                merge_headers(&mut response, unpoly_headers);
+                response.map_into_left_body()
+            }
+            Err(err) => HttpResponse::InternalServerError()
+                .body(format!("Cannot encode Unpoly headers: {err}"))
+                .map_into_right_body(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        rt::System,
+        test::{self, TestRequest},
+        web, App,
+    };
+
+    use super::*;
+
+    fn request() -> TestRequest {
+        TestRequest::default()
+            .insert_header(("X-Up-Version", "3.0.0"))
+            .insert_header(("X-Up-Target", ".main"))
+            .insert_header(("X-Up-Mode", "modal"))
+    }
+
+    #[test]
+    fn test_from_request() {
+        System::new().block_on(async {
+            let (request, mut payload) = request().to_http_parts();
+            let mut unpoly = Unpoly::from_request(&request, &mut payload).await.unwrap();
+
+            assert!(unpoly.is_up());
+            assert_eq!(*unpoly.mode(), crate::LayerMode::MODAL);
+        });
+    }
+
+    #[test]
+    fn test_strict_unpoly() {
+        System::new().block_on(async {
+            let (request, mut payload) = request()
+                .insert_header(("X-Up-Context", "{"))
+                .to_http_parts();
+            let err = StrictUnpoly::from_request(&request, &mut payload)
+                .await
+                .unwrap_err();
+
+            assert_eq!(
+                err.as_response_error().status_code(),
+                StatusCode::BAD_REQUEST
+            );
+        });
+    }
+
+    #[test]
+    fn test_responder() {
+        System::new().block_on(async {
+            let app = test::init_service(App::new().route(
+                "/",
+                web::get().to(|mut unpoly: Unpoly| async move {
+                    unpoly.set_title("Home");
+                    unpoly.target();
+                    UpResponder::new(
+                        unpoly,
+                        HttpResponse::Ok()
+                            .insert_header(("Vary", "Accept-Encoding"))
+                            .body("Home"),
+                    )
+                }),
+            ))
+            .await;
+            let response = test::call_service(&app, request().to_request()).await;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers().get("X-Up-Title").unwrap(), "\"Home\"");
+            assert_eq!(
+                response.headers().get("Vary").unwrap(),
+                "Accept-Encoding,X-Up-Target,X-Up-Version"
+            );
+            assert_eq!(test::read_body(response).await, "Home");
+        });
+    }
+
+    #[test]
+    fn test_responder_invalid_headers() {
+        System::new().block_on(async {
+            let (request, _) = request().to_http_parts();
+            let mut unpoly = Unpoly::from_request_parts(&request_parts(&request));
+            unpoly.set_location("/\n");
+            let response = UpResponder::new(unpoly, "Home").respond_to(&request);
+
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        });
+    }
+}
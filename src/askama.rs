@@ -0,0 +1,176 @@
+use askama::Template;
+
+use crate::{LayerMode, Unpoly};
+
+/// A page template which can render only the blocks for the selectors targeted by an Unpoly request
+///
+/// Declare the blocks of the template with askama's `blocks` attribute, and map selectors to the generated
+/// `as_<block>()` methods with `unpoly::up_template!`:
+///
+/// ```
+/// use askama::Template;
+///
+/// #[derive(Template)]
+/// #[template(
+///     ext = "html",
+///     source = "<html><body>{% block flash %}<div id=\"flash\">{{ flash }}</div>{% endblock %}\
+///               {% block users %}<ul class=\"users\">{% for user in users %}<li>{{ user }}</li>{% endfor %}</ul>{% endblock %}\
+///               </body></html>",
+///     blocks = ["flash", "users"]
+/// )]
+/// struct UsersPage {
+///     flash: String,
+///     users: Vec<String>,
+/// }
+///
+/// unpoly::up_template!(UsersPage {
+///     "#flash" => as_flash,
+///     ".users" => as_users,
+/// });
+/// ```
+///
+/// Implement the trait manually to render different blocks depending on the layer mode, and return `true` from
+/// `depends_on_mode()`.
+pub trait UpTemplate: Template {
+    /// Render the block for a selector of the target, or returns `None` if no block is mapped to the selector
+    fn render_block(&self, selector: &str, mode: &LayerMode) -> Option<askama::Result<String>>;
+
+    /// Returns true if `render_block()` uses the layer mode
+    ///
+    /// Only then the mode of the request is passed, so `X-Up-Mode` is added to the `Vary` header. Otherwise the
+    /// mode is `root`.
+    fn depends_on_mode(&self) -> bool {
+        false
+    }
+}
+
+/// Implement `UpTemplate` for a template by mapping selectors to the block methods generated by askama
+///
+/// See `UpTemplate` for an example.
+#[macro_export]
+macro_rules! up_template {
+    ($template:ty { $($selector:literal => $block:ident),* $(,)? }) => {
+        impl $crate::UpTemplate for $template {
+            fn render_block(
+                &self,
+                selector: &str,
+                _mode: &$crate::LayerMode,
+            ) -> ::std::option::Option<$crate::__askama::Result<::std::string::String>> {
+                match selector {
+                    $($selector => ::std::option::Option::Some(
+                        $crate::__askama::Template::render(&self.$block()),
+                    ),)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+        }
+    };
+}
+
+impl Unpoly {
+    /// Render the blocks of the template which are targeted by the request
+    ///
+    /// The full template is rendered for requests which are not from an Unpoly client, when the whole page is
+    /// targeted, or when a selector without `:maybe` has no block. When nothing is targeted (`:none`), nothing is
+    /// rendered.
+    pub fn render_template<T: UpTemplate>(&mut self, template: &T) -> askama::Result<String> {
+        let Some(target) = self.parsed_target() else {
+            return template.render();
+        };
+        if target.is_none() {
+            return Ok(String::new());
+        }
+        if target.parts().is_empty() || target.is_full_page() {
+            return template.render();
+        }
+
+        let mode = if template.depends_on_mode() {
+            *self.mode()
+        } else {
+            LayerMode::ROOT
+        };
+        let mut html = String::new();
+        for part in target.parts().iter().filter(|part| !part.is_none()) {
+            match template.render_block(&part.selector, &mode) {
+                Some(block) => html.push_str(&block?),
+                None if part.optional => {}
+                None => return template.render(),
+            }
+        }
+        Ok(html)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderMap;
+
+    use super::*;
+
+    #[derive(Template)]
+    #[template(
+        ext = "html",
+        source = "<html><body>{% block flash %}<div id=\"flash\">{{ flash }}</div>{% endblock %}\
+                  {% block users %}<ul class=\"users\"><li>{{ user }}</li></ul>{% endblock %}</body></html>",
+        blocks = ["flash", "users"]
+    )]
+    struct UsersPage {
+        flash: &'static str,
+        user: &'static str,
+    }
+
+    crate::up_template!(UsersPage {
+        "#flash" => as_flash,
+        ".users" => as_users,
+    });
+
+    fn render(target: Option<&str>) -> String {
+        let mut headers = HeaderMap::new();
+        if let Some(target) = target {
+            headers.insert("X-Up-Version", "3.0.0".parse().unwrap());
+            headers.insert("X-Up-Target", target.parse().unwrap());
+        }
+        let page = UsersPage {
+            flash: "Saved",
+            user: "<Bob>",
+        };
+        Unpoly::from_headers(&headers)
+            .render_template(&page)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_render_template() {
+        const FULL: &str = "<html><body><div id=\"flash\">Saved</div>\
+                            <ul class=\"users\"><li>&#60;Bob&#62;</li></ul></body></html>";
+
+        assert_eq!(
+            render(Some(".users, #flash:maybe")),
+            "<ul class=\"users\"><li>&#60;Bob&#62;</li></ul><div id=\"flash\">Saved</div>"
+        );
+        assert_eq!(
+            render(Some(".users, #missing:maybe")),
+            "<ul class=\"users\"><li>&#60;Bob&#62;</li></ul>"
+        );
+        assert_eq!(render(Some(":none")), "");
+        for target in [None, Some("body"), Some(".users, #missing")] {
+            assert_eq!(render(target), FULL, "target {target:?}");
+        }
+    }
+
+    #[test]
+    fn test_render_template_vary() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Up-Version", "3.0.0".parse().unwrap());
+        headers.insert("X-Up-Target", ".users".parse().unwrap());
+        headers.insert("X-Up-Mode", "modal".parse().unwrap());
+        let page = UsersPage {
+            flash: "Saved",
+            user: "Bob",
+        };
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        unpoly.render_template(&page).unwrap();
+        assert_eq!(unpoly.get_headers().unwrap()["Vary"], "X-Up-Target");
+    }
+}
@@ -1,10 +1,10 @@
-use crate::headers;
-use crate::LayerMode;
-use crate::Unpoly;
+use crate::headers::merge_headers;
+use crate::{StrictUnpoly, Unpoly, UnpolyHandle};
 
 use axum::{
     extract::FromRequestParts,
     http::{request::Parts, StatusCode},
+    response::{IntoResponse, IntoResponseParts, Redirect, Response, ResponseParts},
 };
 
 impl<S> FromRequestParts<S> for Unpoly
@@ -14,77 +14,142 @@ where
     type Rejection = (StatusCode, &'static str);
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        let request_version = parts
-            .headers
-            .get(headers::VERSION)
-            .map(|v| v.to_str().map_or(None, |v| Some(v.to_string())))
-            .unwrap_or(None);
-
-        let request_context: Option<serde_json::Value> = parts
-            .headers
-            .get(headers::CONTEXT)
-            .map(|v| v.to_str().ok())
-            .unwrap_or(None)
-            .map(|v| serde_json::from_str(v).unwrap_or_default());
-
-        let request_fail_context: Option<serde_json::Value> = parts
-            .headers
-            .get(headers::FAIL_CONTEXT)
-            .map(|v| v.to_str().ok())
-            .unwrap_or(None)
-            .map(|v| serde_json::from_str(v).unwrap_or_default());
-
-        let request_mode = parts
-            .headers
-            .get(headers::MODE)
-            .map(|v| {
-                v.to_str().map_or(LayerMode::ROOT, |v| {
-                    serde_json::from_str(&("\"".to_string() + v + "\"")).unwrap_or_default()
-                })
-            })
-            .unwrap_or(LayerMode::ROOT);
-
-        let request_fail_mode = parts
-            .headers
-            .get(headers::FAIL_MODE)
-            .map(|v| {
-                v.to_str().map_or(LayerMode::ROOT, |v| {
-                    serde_json::from_str(&("\"".to_string() + v + "\"")).unwrap_or_default()
-                })
-            })
-            .unwrap_or(LayerMode::ROOT);
-
-        let request_target = parts
-            .headers
-            .get(headers::TARGET)
-            .map(|v| v.to_str().map_or(None, |v| Some(v.to_string())))
-            .unwrap_or(None);
-
-        let request_fail_target = parts
-            .headers
-            .get(headers::FAIL_TARGET)
-            .map(|v| v.to_str().map_or(None, |v| Some(v.to_string())))
-            .unwrap_or(None);
-
-        let request_validate = parts.headers.get(headers::VALIDATE).map_or(vec![], |v| {
-            v.to_str()
-                .unwrap_or("")
-                .split_whitespace()
-                .map(|v| v.trim().to_string())
-                .collect()
-        });
-
-        Ok(Unpoly {
-            request_version,
-            request_context,
-            request_fail_context,
-            request_fail_mode,
-            request_mode,
-            request_target,
-            request_fail_target,
-            request_validate,
-            ..Default::default()
-        })
+        // Behind `UnpolyLayer` the request is already parsed
+        if let Some(handle) = parts.extensions.get::<UnpolyHandle>() {
+            return Ok(handle.extract());
+        }
+        Ok(Unpoly::from_request_parts(parts))
+    }
+}
+
+impl Unpoly {
+    /// Redirect (`303 See Other`) to the location, passing the Unpoly request state to the redirect target
+    ///
+    /// See `Unpoly::redirect_location()`
+    pub fn redirect(&self, location: &str) -> Redirect {
+        Redirect::to(&self.redirect_location(location))
+    }
+}
+
+/// Add the Unpoly headers to a response, so a handler can return `(unpoly, html)`
+///
+/// When the headers cannot be encoded, the response is a `500 Internal Server Error` with the reason.
+impl IntoResponseParts for Unpoly {
+    type Error = (StatusCode, String);
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        if self.handle.is_some() {
+            // `UnpolyLayer` adds the headers after this object is written back to its handle
+            return Ok(res);
+        }
+        let headers = self.get_headers().map_err(|err| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Cannot encode Unpoly headers: {err}"),
+            )
+        })?;
+        merge_headers(res.headers_mut(), headers);
+        Ok(res)
+    }
+}
+
+/// A response with only the Unpoly headers, e.g. after `render_nothing()`
+impl IntoResponse for Unpoly {
+    fn into_response(self) -> Response {
+        (self, ()).into_response()
+    }
+}
+
+/// A response with a body, the Unpoly headers and a status code matching the success of the Unpoly object
+///
+/// Unpoly uses the fail target and fail layer for responses with an error status, e.g. to re-render a form with
+/// validation errors. See <https://unpoly.com/failed-responses>
+///
+/// ```
+/// use axum::response::IntoResponse;
+///
+/// fn handler(unpoly: unpoly::Unpoly) -> impl IntoResponse {
+///     let valid: bool = todo!("validate form");
+///     let html: String = todo!("render form");
+///     if valid {
+///         unpoly::UpResponse::success(unpoly, html)
+///     } else {
+///         unpoly::UpResponse::failure(unpoly, html)
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct UpResponse<T> {
+    unpoly: Unpoly,
+    body: T,
+    status: StatusCode,
+}
+
+impl<T> UpResponse<T> {
+    /// Create a response with status `422 Unprocessable Entity` if the Unpoly object has failed (see
+    /// `Unpoly::set_success()`), otherwise `200 OK`
+    pub fn new(mut unpoly: Unpoly, body: T) -> Self {
+        let status = match unpoly.success() {
+            Some(false) => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::OK,
+        };
+        UpResponse {
+            unpoly,
+            body,
+            status,
+        }
+    }
+
+    /// Create a successful response with status `200 OK`
+    pub fn success(mut unpoly: Unpoly, body: T) -> Self {
+        unpoly.set_success(true);
+        Self::new(unpoly, body)
+    }
+
+    /// Create a failed response with status `422 Unprocessable Entity`
+    pub fn failure(mut unpoly: Unpoly, body: T) -> Self {
+        unpoly.set_success(false);
+        Self::new(unpoly, body)
+    }
+
+    /// Use another status code, e.g. `201 Created` or `400 Bad Request`
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for UpResponse<T> {
+    fn into_response(self) -> Response {
+        (self.status, self.unpoly, self.body).into_response()
+    }
+}
+
+impl<S> FromRequestParts<S> for StrictUnpoly
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(handle) = parts.extensions.get::<UnpolyHandle>() {
+            return handle
+                .extract_strict()
+                .map(StrictUnpoly)
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()));
+        }
+        Unpoly::try_from_request_parts(parts)
+            .map(StrictUnpoly)
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))
+    }
+}
+
+/// Add the Unpoly headers to a response, like `Unpoly`
+impl IntoResponseParts for StrictUnpoly {
+    type Error = (StatusCode, String);
+
+    fn into_response_parts(self, res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        self.0.into_response_parts(res)
     }
 }
 
@@ -94,6 +159,7 @@ mod tests {
     use axum::{body::Body, http::Request};
 
     use super::*;
+    use crate::LayerMode;
 
     #[tokio::test]
     async fn test_no_unpoly_request() {
@@ -105,13 +171,16 @@ mod tests {
             .unwrap();
         let mut parts = request.into_parts();
 
-        let mut unpoly = Unpoly::from_request_parts(&mut parts.0, &()).await.unwrap();
+        let mut unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
 
         assert_eq!(unpoly.request_version, None);
         assert_eq!(unpoly.request_context, None);
         assert_eq!(unpoly.request_fail_context, None);
-        assert_eq!(unpoly.request_fail_mode, LayerMode::ROOT);
-        assert_eq!(unpoly.request_mode, LayerMode::ROOT);
+        assert_eq!(unpoly.request_fail_mode, None);
+        assert_eq!(unpoly.request_mode, None);
+        assert_eq!(unpoly.request_origin_mode, None);
         assert_eq!(unpoly.request_target, None);
 
         assert!(!unpoly.is_up());
@@ -129,13 +198,16 @@ mod tests {
             .unwrap();
         let mut parts = request.into_parts();
 
-        let mut unpoly = Unpoly::from_request_parts(&mut parts.0, &()).await.unwrap();
+        let mut unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
 
         assert_eq!(unpoly.request_version, Some("1.0.0".to_string()));
         assert_eq!(unpoly.request_context, None);
         assert_eq!(unpoly.request_fail_context, None);
-        assert_eq!(unpoly.request_fail_mode, LayerMode::ROOT);
-        assert_eq!(unpoly.request_mode, LayerMode::ROOT);
+        assert_eq!(unpoly.request_fail_mode, None);
+        assert_eq!(unpoly.request_mode, None);
+        assert_eq!(unpoly.request_origin_mode, None);
         assert_eq!(unpoly.request_target, None);
 
         unpoly.is_up();
@@ -147,7 +219,9 @@ mod tests {
             "X-Up-Mode,X-Up-Target,X-Up-Version".to_string()
         );
 
-        let mut unpoly = Unpoly::from_request_parts(&mut parts.0, &()).await.unwrap();
+        let mut unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
         unpoly.is_up();
         unpoly.set_success(false);
         unpoly.mode();
@@ -175,7 +249,9 @@ mod tests {
             .unwrap();
         let mut parts = request.into_parts();
 
-        let mut unpoly = Unpoly::from_request_parts(&mut parts.0, &()).await.unwrap();
+        let mut unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
         unpoly.set_success(true);
 
         unpoly.is_up();
@@ -206,7 +282,9 @@ mod tests {
             .unwrap();
         let mut parts = request.into_parts();
 
-        let mut unpoly = Unpoly::from_request_parts(&mut parts.0, &()).await.unwrap();
+        let mut unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
         unpoly.set_success(false);
 
         unpoly.is_up();
@@ -237,9 +315,13 @@ mod tests {
             .unwrap();
         let mut parts = request.into_parts();
 
-        let mut unpoly = Unpoly::from_request_parts(&mut parts.0, &()).await.unwrap();
+        let mut unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
 
-        unpoly.set_context(serde_json::json!({"lives": 43}));
+        unpoly
+            .set_context(serde_json::json!({"lives": 43}))
+            .unwrap();
 
         unpoly.set_title("Hello");
         unpoly.set_location("https://unpoly.com/");
@@ -257,4 +339,208 @@ mod tests {
         assert_eq!(unpoly.get_headers().unwrap()["X-Up-Evict-Cache"], "main");
         assert_eq!(unpoly.get_headers().unwrap()["X-Up-Expire-Cache"], "main");
     }
+
+    #[tokio::test]
+    async fn test_strict_unpoly() {
+        let request = Request::builder()
+            .method("GET")
+            .uri("https://www.unpoly.com/")
+            .header("X-Up-Version", "1.0.0")
+            .header("X-Up-Mode", "modal")
+            .body(Body::empty())
+            .unwrap();
+        let mut parts = request.into_parts();
+
+        let mut unpoly = StrictUnpoly::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+        assert_eq!(*unpoly.mode(), LayerMode::MODAL);
+
+        let response = (unpoly, "content").into_response();
+        assert_eq!(response.headers()["Vary"], "X-Up-Mode");
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("https://www.unpoly.com/")
+            .header("X-Up-Version", "1.0.0")
+            .header("X-Up-Fail-Context", "{\"lives\": }")
+            .body(Body::empty())
+            .unwrap();
+        let mut parts = request.into_parts();
+
+        let (status, body) = StrictUnpoly::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.starts_with("Header X-Up-Fail-Context does not contain valid JSON"));
+    }
+
+    #[tokio::test]
+    async fn test_unpoly_origin_mode() {
+        let request = Request::builder()
+            .method("GET")
+            .uri("https://www.unpoly.com/")
+            .header("X-Up-Version", "3.0.0")
+            .header("X-Up-Mode", "root")
+            .header("X-Up-Origin-Mode", "modal")
+            .body(Body::empty())
+            .unwrap();
+        let mut parts = request.into_parts();
+
+        let mut unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+
+        assert_eq!(*unpoly.mode(), LayerMode::ROOT);
+        assert_eq!(*unpoly.origin_mode(), LayerMode::MODAL);
+
+        assert_eq!(
+            unpoly.get_headers().unwrap()["Vary"],
+            "X-Up-Mode,X-Up-Origin-Mode".to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unpoly_redirect() {
+        use axum::response::IntoResponse;
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("https://www.unpoly.com/users")
+            .header("X-Up-Version", "3.0.0")
+            .header("X-Up-Target", ".users")
+            .body(Body::empty())
+            .unwrap();
+        let mut parts = request.into_parts();
+
+        let unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+        let response = unpoly.redirect("/users/1").into_response();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let location = response.headers()["Location"].to_str().unwrap();
+        assert!(location.starts_with("/users/1?_up_version=3.0.0&_up_target=.users"));
+
+        let request = Request::builder()
+            .method("GET")
+            .uri(location)
+            .header("X-Up-Version", "3.0.0")
+            .header("X-Up-Target", ".users")
+            .body(Body::empty())
+            .unwrap();
+        let mut parts = request.into_parts();
+
+        let unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+        let headers = unpoly.get_headers().unwrap();
+        assert_eq!(headers["X-Up-Location"], "/users/1");
+        assert_eq!(headers["X-Up-Method"], "GET");
+    }
+
+    #[tokio::test]
+    async fn test_unpoly_method_cookie() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("https://www.unpoly.com/users")
+            .body(Body::empty())
+            .unwrap();
+        let mut parts = request.into_parts();
+
+        let unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+        assert_eq!(
+            unpoly.get_headers().unwrap()["Set-Cookie"],
+            "_up_method=POST; Path=/"
+        );
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("https://www.unpoly.com/users")
+            .header("Cookie", "session=1; _up_method=POST")
+            .body(Body::empty())
+            .unwrap();
+        let mut parts = request.into_parts();
+
+        let unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+        assert_eq!(
+            unpoly.get_headers().unwrap()["Set-Cookie"],
+            "_up_method=; Path=/; Max-Age=0"
+        );
+
+        // A HEAD request is a GET request without body
+        let request = Request::builder()
+            .method("HEAD")
+            .uri("https://www.unpoly.com/users")
+            .body(Body::empty())
+            .unwrap();
+        let mut parts = request.into_parts();
+
+        let unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+        assert!(!unpoly.get_headers().unwrap().contains_key("Set-Cookie"));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("https://www.unpoly.com/users")
+            .header("X-Up-Version", "3.0.0")
+            .body(Body::empty())
+            .unwrap();
+        let mut parts = request.into_parts();
+
+        let unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+        assert!(!unpoly.get_headers().unwrap().contains_key("Set-Cookie"));
+    }
+
+    #[tokio::test]
+    async fn test_unpoly_into_response() {
+        let request = Request::builder()
+            .method("GET")
+            .uri("https://www.unpoly.com/")
+            .header("X-Up-Version", "3.0.0")
+            .header("X-Up-Target", ".main")
+            .header("X-Up-Fail-Target", "form")
+            .body(Body::empty())
+            .unwrap();
+        let mut parts = request.into_parts();
+
+        let mut unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+        unpoly.set_title("Hello");
+        let response = (unpoly, "Hello").into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-Up-Title"], "\"Hello\"");
+
+        let unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+        let response = UpResponse::failure(unpoly, "Invalid").into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.headers()["X-Up-Target"], "form");
+        assert_eq!(response.headers()["Vary"], "X-Up-Fail-Target");
+
+        let unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+        let response = UpResponse::success(unpoly, "Created")
+            .with_status(StatusCode::CREATED)
+            .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["X-Up-Target"], ".main");
+
+        let mut unpoly = <Unpoly as FromRequestParts<()>>::from_request_parts(&mut parts.0, &())
+            .await
+            .unwrap();
+        unpoly.set_location("/\n");
+        let response = (unpoly, "Hello").into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
 }
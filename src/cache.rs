@@ -0,0 +1,76 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::Error;
+
+/// A URL pattern for `X-Up-Expire-Cache` and `X-Up-Evict-Cache`, like `/users/*`
+///
+/// A pattern may contain `*` wildcards, but no whitespace, since multiple patterns are separated by spaces.
+///
+/// See <https://unpoly.com/url-patterns>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachePattern(String);
+
+impl CachePattern {
+    /// Create a validated cache pattern
+    pub fn new(pattern: impl Into<String>) -> Result<Self, Error> {
+        let pattern = pattern.into();
+        if pattern.is_empty()
+            || pattern == "false"
+            || !pattern.chars().all(|c| c.is_ascii_graphic())
+        {
+            return Err(Error::InvalidCachePattern(pattern));
+        }
+        Ok(CachePattern(pattern))
+    }
+
+    /// Create a cache pattern without validation
+    pub(crate) fn new_unchecked(pattern: impl Into<String>) -> Self {
+        CachePattern(pattern.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for CachePattern {
+    type Err = Error;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        CachePattern::new(pattern)
+    }
+}
+
+impl fmt::Display for CachePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Join the patterns into a header value
+pub(crate) fn join(patterns: &[CachePattern]) -> String {
+    patterns
+        .iter()
+        .map(CachePattern::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_pattern() {
+        assert_eq!(CachePattern::new("/users/*").unwrap().as_str(), "/users/*");
+        assert_eq!("*".parse::<CachePattern>().unwrap().to_string(), "*");
+
+        for invalid in ["", "false", "/users /tasks", "/caf\u{e9}", "/users\n"] {
+            assert!(matches!(
+                CachePattern::new(invalid),
+                Err(Error::InvalidCachePattern(_))
+            ));
+        }
+    }
+}
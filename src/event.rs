@@ -0,0 +1,57 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{Error, MatchingLayer};
+
+/// An event which can be emitted to the frontend via `Unpoly::emit()`
+///
+/// With the `derive` feature, this trait can be derived:
+///
+/// ```ignore
+/// #[derive(Serialize, unpoly::UpEvent)]
+/// #[up_event(name = "user:created")]
+/// struct UserCreated {
+///     id: u32,
+/// }
+/// ```
+///
+/// See <https://unpoly.com/X-Up-Events>
+pub trait UpEvent: Serialize {
+    /// The type of the event, like `user:created`
+    const TYPE: &'static str;
+}
+
+/// Options for an emitted event
+///
+/// ```
+/// let options = unpoly::EventOptions::new().layer(unpoly::MatchingLayer::PARENT);
+/// ```
+#[derive(Debug, Default)]
+pub struct EventOptions {
+    layer: Option<MatchingLayer>,
+}
+
+impl EventOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Emit the event on the given layer instead of the current layer
+    pub fn layer(mut self, layer: MatchingLayer) -> Self {
+        self.layer = Some(layer);
+        self
+    }
+
+    pub(crate) fn apply(self, event: &mut Map<String, Value>) -> Result<(), Error> {
+        if let Some(layer) = self.layer {
+            event.insert(
+                "layer".to_string(),
+                match layer {
+                    MatchingLayer::INDEX(index) => Value::Number(index.into()),
+                    other => serde_json::to_value(other)?,
+                },
+            );
+        }
+        Ok(())
+    }
+}
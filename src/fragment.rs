@@ -0,0 +1,246 @@
+use scraper::{Html, Selector};
+
+use crate::{Target, Unpoly};
+
+/// Extract the elements targeted by an Unpoly request from a rendered HTML document
+///
+/// Returns the first element matching each selector of the target, preceded by the `<title>` of the document, so
+/// a handler can render the full page while only the targeted fragments are sent. The full document is returned
+/// when it cannot be reduced safely:
+/// - the target is empty or targets the whole page (`html` or `body`)
+/// - the target contains `:main`, `:layer` or a selector which cannot be evaluated on the server (like `:origin`)
+/// - a selector without `:maybe` matches nothing, so the Unpoly client can report the missing fragment
+/// - a selector no longer matches the extracted fragments, like `.sidebar .item` when only the `.item` is sent
+///
+/// When nothing is targeted (`:none`), an empty string is returned.
+///
+/// ```
+/// let html = "<html><head><title>Users</title></head><body><nav>…</nav><main>Users</main></body></html>";
+/// let target = unpoly::Target::parse("main");
+/// assert_eq!(
+///     unpoly::extract_fragments(html, &target),
+///     "<title>Users</title><main>Users</main>"
+/// );
+/// ```
+pub fn extract_fragments(html: &str, target: &Target) -> String {
+    if target.is_none() {
+        return String::new();
+    }
+    if target.parts().is_empty()
+        || target.is_full_page()
+        || target.contains("head")
+        || target
+            .parts()
+            .iter()
+            .any(|part| part.is_main() || part.is_layer())
+    {
+        return html.to_string();
+    }
+
+    let document = Html::parse_document(html);
+    let mut included = vec![];
+    let mut matched = vec![];
+    for part in target.parts().iter().filter(|part| !part.is_none()) {
+        let Ok(selector) = Selector::parse(&part.selector) else {
+            return html.to_string();
+        };
+        match document.select(&selector).next() {
+            Some(element) => {
+                matched.push(selector);
+                // An element within an already included element is sent along with it
+                let is_included = std::iter::once(*element)
+                    .chain(element.ancestors())
+                    .any(|node| included.contains(&node.id()));
+                if !is_included {
+                    included.retain(|id| {
+                        !document
+                            .tree
+                            .get(*id)
+                            .is_some_and(|node| node.ancestors().any(|a| a.id() == element.id()))
+                    });
+                    included.push(element.id());
+                }
+            }
+            None if part.optional => {}
+            None => return html.to_string(),
+        }
+    }
+
+    let mut fragments = String::new();
+    if let Some(title) = Selector::parse("title")
+        .ok()
+        .and_then(|selector| document.select(&selector).next())
+    {
+        fragments.push_str(&title.html());
+    }
+    for id in included {
+        if let Some(element) = document.tree.get(id).and_then(scraper::ElementRef::wrap) {
+            fragments.push_str(&element.html());
+        }
+    }
+
+    // Selectors which depend on ancestors or siblings, like `.sidebar .item`, may not match the fragments on their own
+    let reduced = Html::parse_document(&fragments);
+    if matched
+        .iter()
+        .any(|selector| reduced.select(selector).next().is_none())
+    {
+        return html.to_string();
+    }
+    fragments
+}
+
+/// Returns the selectors of the target which are required, but match no element of the HTML document
+///
+/// Selectors which cannot be evaluated on the server, like `:main`, are assumed to match.
+#[cfg(feature = "axum")]
+pub(crate) fn missing_selectors(html: &str, target: &Target) -> Vec<String> {
+    let document = Html::parse_document(html);
+    target
+        .parts()
+        .iter()
+        .filter(|part| !(part.optional || part.is_none() || part.is_main() || part.is_layer()))
+        .filter(|part| {
+            Selector::parse(&part.selector)
+                .is_ok_and(|selector| document.select(&selector).next().is_none())
+        })
+        .map(|part| part.selector.clone())
+        .collect()
+}
+
+/// Returns the text of the `<title>` of the HTML document, with whitespace collapsed like `document.title`
+#[cfg(feature = "axum")]
+pub(crate) fn title(html: &str) -> Option<String> {
+    let document = Html::parse_document(html);
+    let selector = Selector::parse("title").ok()?;
+    let title = document.select(&selector).next()?;
+    Some(
+        title
+            .text()
+            .collect::<String>()
+            .split_ascii_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+impl Unpoly {
+    /// Extract the elements targeted by the request from a rendered HTML document, see `unpoly::extract_fragments()`
+    ///
+    /// For requests which are not from an Unpoly client, the full document is returned.
+    pub fn extract_fragments(&mut self, html: &str) -> String {
+        match self.parsed_target() {
+            Some(target) => extract_fragments(html, &target),
+            None => html.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = "<!DOCTYPE html><html><head><title>Users</title></head><body>\
+        <nav id=\"nav\">Menu</nav><div id=\"flash\"></div>\
+        <main class=\"content\"><h1>Users</h1><ul class=\"users\"><li>Bob</li></ul></main>\
+        </body></html>";
+
+    fn extract(target: &str) -> String {
+        extract_fragments(HTML, &Target::parse(target))
+    }
+
+    #[test]
+    fn test_extract_fragments() {
+        assert_eq!(
+            extract(".content:after, #flash"),
+            "<title>Users</title>\
+             <main class=\"content\"><h1>Users</h1><ul class=\"users\"><li>Bob</li></ul></main>\
+             <div id=\"flash\"></div>"
+        );
+        assert_eq!(
+            extract(".content, .users, #missing:maybe"),
+            "<title>Users</title>\
+             <main class=\"content\"><h1>Users</h1><ul class=\"users\"><li>Bob</li></ul></main>"
+        );
+        assert_eq!(
+            extract(".users, #flash, .content"),
+            "<title>Users</title><div id=\"flash\"></div>\
+             <main class=\"content\"><h1>Users</h1><ul class=\"users\"><li>Bob</li></ul></main>"
+        );
+    }
+
+    #[test]
+    fn test_extract_fragments_full_document() {
+        for target in [
+            "",
+            "body",
+            "html",
+            ":main",
+            "#nav, :layer",
+            "#missing",
+            "&.active",
+        ] {
+            assert_eq!(extract(target), HTML, "target {target:?}");
+        }
+        assert_eq!(extract(":none"), "");
+    }
+
+    #[test]
+    fn test_extract_fragments_context_selectors() {
+        let html = "<html><head><title>T</title></head><body>\
+            <div class=\"sidebar\"><a class=\"item\">x</a></div>\
+            <main><h1>Title</h1></main><nav id=\"nav\"></nav><ul><li>1</li><li>2</li></ul>\
+            </body></html>";
+        for target in [
+            ".sidebar .item",
+            "main > h1",
+            "#nav + ul",
+            "li:nth-child(2)",
+            "#nav, .sidebar .item",
+        ] {
+            assert_eq!(
+                extract_fragments(html, &Target::parse(target)),
+                html,
+                "target {target:?}"
+            );
+        }
+        assert_eq!(
+            extract_fragments(html, &Target::parse("main > h1, main")),
+            "<title>T</title><main><h1>Title</h1></main>"
+        );
+    }
+
+    #[cfg(feature = "axum")]
+    #[test]
+    fn test_missing_selectors() {
+        let target = Target::parse(".content, #missing, #optional:maybe, :main, &.active, .other");
+        assert_eq!(missing_selectors(HTML, &target), ["#missing", ".other"]);
+        assert!(missing_selectors(HTML, &Target::parse("body, #nav:after")).is_empty());
+    }
+
+    #[cfg(feature = "axum")]
+    #[test]
+    fn test_title() {
+        assert_eq!(title(HTML).unwrap(), "Users");
+        assert_eq!(
+            title("<title>\n  Users &amp;\n Tasks </title>").unwrap(),
+            "Users & Tasks"
+        );
+        assert!(title("<main>Users</main>").is_none());
+    }
+
+    #[test]
+    fn test_unpoly_extract_fragments() {
+        let mut headers = http::HeaderMap::new();
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert_eq!(unpoly.extract_fragments(HTML), HTML);
+
+        headers.insert("X-Up-Version", "3.0.0".parse().unwrap());
+        headers.insert("X-Up-Target", "#nav".parse().unwrap());
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert_eq!(
+            unpoly.extract_fragments(HTML),
+            "<title>Users</title><nav id=\"nav\">Menu</nav>"
+        );
+    }
+}
@@ -3,6 +3,7 @@ pub(crate) const FAIL_CONTEXT: &str = "X-Up-Fail-Context";
 pub(crate) const CONTEXT: &str = "X-Up-Context";
 pub(crate) const FAIL_MODE: &str = "X-Up-Fail-Mode";
 pub(crate) const MODE: &str = "X-Up-Mode";
+pub(crate) const ORIGIN_MODE: &str = "X-Up-Origin-Mode";
 pub(crate) const FAIL_TARGET: &str = "X-Up-Fail-Target";
 pub(crate) const TARGET: &str = "X-Up-Target";
 pub(crate) const VALIDATE: &str = "X-Up-Validate";
@@ -12,6 +13,262 @@ pub(crate) const EXPIRE_CACHE: &str = "X-Up-Expire-Cache";
 pub(crate) const EVENTS: &str = "X-Up-Events";
 pub(crate) const ACCEPT_LAYER: &str = "X-Up-Accept-Layer";
 pub(crate) const DISMISS_LAYER: &str = "X-Up-Dismiss-Layer";
+pub(crate) const OPEN_LAYER: &str = "X-Up-Open-Layer";
 pub(crate) const LOCATION: &str = "X-Up-Location";
 pub(crate) const TITLE: &str = "X-Up-Title";
 pub(crate) const VARY: &str = "Vary";
+pub(crate) const IF_MODIFIED_SINCE: &str = "If-Modified-Since";
+pub(crate) const COOKIE: &str = "Cookie";
+pub(crate) const SET_COOKIE: &str = "Set-Cookie";
+
+/// The cookie which tells the Unpoly client that the page was loaded with a non-GET request
+pub(crate) const METHOD_COOKIE: &str = "_up_method";
+
+// Headers of the Unpoly 2 protocol, which are replaced in Unpoly 3
+pub(crate) const RELOAD_FROM_TIME: &str = "X-Up-Reload-From-Time";
+pub(crate) const CLEAR_CACHE: &str = "X-Up-Clear-Cache";
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::{HeaderMap, HeaderValue};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{version, Error, LayerMode, Version};
+
+/// Parser for the Unpoly request headers
+///
+/// In strict mode, malformed headers result in an error. Otherwise they fall back to
+/// the same defaults as an absent header (except for invalid JSON, which becomes `null`).
+pub(crate) struct HeaderParser<'a> {
+    pub(crate) headers: &'a HeaderMap,
+    pub(crate) strict: bool,
+}
+
+impl<'a> HeaderParser<'a> {
+    pub(crate) fn str(&self, name: &'static str) -> Result<Option<&'a str>, Error> {
+        match self.headers.get(name).map(|v| v.to_str()) {
+            None => Ok(None),
+            Some(Ok(v)) => Ok(Some(v)),
+            Some(Err(_)) if self.strict => Err(Error::InvalidHeaderEncoding { header: name }),
+            Some(Err(_)) => Ok(None),
+        }
+    }
+
+    pub(crate) fn string(&self, name: &'static str) -> Result<Option<String>, Error> {
+        Ok(self.str(name)?.map(str::to_string))
+    }
+
+    pub(crate) fn json(&self, name: &'static str) -> Result<Option<Value>, Error> {
+        self.str(name)?
+            .map(|v| match serde_json::from_str(v) {
+                Ok(v) => Ok(v),
+                Err(source) if self.strict => Err(Error::InvalidHeaderJson {
+                    header: name,
+                    source,
+                }),
+                Err(_) => Ok(Value::Null),
+            })
+            .transpose()
+    }
+
+    pub(crate) fn version(&self, name: &'static str) -> Result<Option<Version>, Error> {
+        self.str(name)?
+            .map(|v| match version::parse_version(v) {
+                Some(version) => Ok(Some(version)),
+                None if self.strict => Err(Error::InvalidHeaderVersion {
+                    header: name,
+                    value: v.to_string(),
+                }),
+                None => Ok(None),
+            })
+            .transpose()
+            .map(Option::flatten)
+    }
+
+    /// Parse a header with the number of seconds since the Unix epoch, like `X-Up-Reload-From-Time`
+    pub(crate) fn unix_time(&self, name: &'static str) -> Result<Option<SystemTime>, Error> {
+        self.time(name, |v| {
+            v.trim()
+                .parse()
+                .ok()
+                .and_then(|secs| UNIX_EPOCH.checked_add(Duration::from_secs(secs)))
+        })
+    }
+
+    /// Parse a header with an HTTP date, like `If-Modified-Since`
+    pub(crate) fn http_date(&self, name: &'static str) -> Result<Option<SystemTime>, Error> {
+        self.time(name, |v| httpdate::parse_http_date(v).ok())
+    }
+
+    fn time(
+        &self,
+        name: &'static str,
+        parse: impl Fn(&str) -> Option<SystemTime>,
+    ) -> Result<Option<SystemTime>, Error> {
+        self.str(name)?
+            .map(|v| match parse(v) {
+                Some(time) => Ok(Some(time)),
+                None if self.strict => Err(Error::InvalidHeaderTime {
+                    header: name,
+                    value: v.to_string(),
+                }),
+                None => Ok(None),
+            })
+            .transpose()
+            .map(Option::flatten)
+    }
+
+    pub(crate) fn mode(&self, name: &'static str) -> Result<Option<LayerMode>, Error> {
+        match self.str(name)? {
+            None => Ok(None),
+            Some(v) => match serde_json::from_value(Value::String(v.to_string())) {
+                Ok(mode) => Ok(Some(mode)),
+                Err(_) if self.strict => Err(Error::InvalidHeaderLayerMode {
+                    header: name,
+                    value: v.to_string(),
+                }),
+                Err(_) => Ok(Some(LayerMode::ROOT)),
+            },
+        }
+    }
+
+    /// Returns true if the `Cookie` header contains the cookie with the given name
+    pub(crate) fn has_cookie(&self, cookie: &str) -> bool {
+        self.headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .any(|pair| pair.split('=').next().map(str::trim) == Some(cookie))
+    }
+
+    pub(crate) fn list(&self, name: &'static str) -> Result<Vec<String>, Error> {
+        Ok(self.str(name)?.map_or(vec![], |v| {
+            v.split_whitespace().map(|v| v.trim().to_string()).collect()
+        }))
+    }
+}
+
+/// Merge the Unpoly headers into the headers of a response
+///
+/// `Vary` is combined with the existing `Vary` header and cookies are appended; other headers are replaced.
+pub(crate) fn merge_headers(response: &mut HeaderMap, headers: HeaderMap) {
+    let mut name = None;
+    for (key, value) in headers {
+        // Only the first value of a header has a name, see `HeaderMap::into_iter()`
+        name = key.or(name);
+        let Some(name) = name.clone() else {
+            continue;
+        };
+        if name == http::header::VARY {
+            let vary = merge_vary(
+                response
+                    .get_all(&name)
+                    .iter()
+                    .chain([&value])
+                    .filter_map(|v| v.to_str().ok()),
+            );
+            if let Ok(vary) = HeaderValue::from_str(&vary) {
+                response.insert(name, vary);
+            }
+        } else if name == http::header::SET_COOKIE {
+            response.append(name, value);
+        } else {
+            response.insert(name, value);
+        }
+    }
+}
+
+/// Combine `Vary` header values into one value, without duplicates
+pub(crate) fn merge_vary<'a>(values: impl IntoIterator<Item = &'a str>) -> String {
+    values
+        .into_iter()
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .fold(Vec::<&str>::new(), |mut vary, v| {
+            if !vary.iter().any(|existing| existing.eq_ignore_ascii_case(v)) {
+                vary.push(v);
+            }
+            vary
+        })
+        .join(",")
+}
+
+/// Encode a plain string as header value, like the URL in `X-Up-Location`
+pub(crate) fn plain_value(name: &'static str, value: &str) -> Result<HeaderValue, Error> {
+    HeaderValue::from_str(value).map_err(|_| Error::InvalidHeaderValue { header: name })
+}
+
+/// Encode a value as JSON header value, like the events in `X-Up-Events`
+///
+/// Non-ASCII characters are escaped as `\uXXXX`, so the header value is always valid.
+pub(crate) fn json_value<S: Serialize + ?Sized>(
+    name: &'static str,
+    value: &S,
+) -> Result<HeaderValue, Error> {
+    plain_value(name, &ascii_json(value)?)
+}
+
+/// Serialize a value into JSON with all non-ASCII and DEL characters escaped
+///
+/// These characters can only occur within JSON strings, so escaping them keeps the JSON valid.
+pub(crate) fn ascii_json<S: Serialize + ?Sized>(value: &S) -> Result<String, Error> {
+    let json = serde_json::to_string(value)?;
+    let mut escaped = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() && c != '\x7f' {
+            escaped.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                escaped.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
+    }
+    Ok(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ascii_json() {
+        assert_eq!(
+            ascii_json("Caf\u{e9} \u{1f600}\n\x7f").unwrap(),
+            "\"Caf\\u00e9 \\ud83d\\ude00\\n\\u007f\""
+        );
+        assert_eq!(
+            serde_json::from_str::<String>(&ascii_json("Caf\u{e9} \u{1f600}").unwrap()).unwrap(),
+            "Caf\u{e9} \u{1f600}"
+        );
+    }
+
+    #[test]
+    fn test_merge_headers() {
+        let mut response = HeaderMap::new();
+        response.insert(VARY, "Accept-Encoding, X-Up-Target".parse().unwrap());
+        response.insert(SET_COOKIE, "session=1".parse().unwrap());
+        response.insert(TITLE, "\"Old\"".parse().unwrap());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, "X-Up-Target,X-Up-Mode".parse().unwrap());
+        headers.insert(SET_COOKIE, "_up_method=POST; Path=/".parse().unwrap());
+        headers.insert(TITLE, "\"New\"".parse().unwrap());
+        merge_headers(&mut response, headers);
+
+        assert_eq!(response[VARY], "Accept-Encoding,X-Up-Target,X-Up-Mode");
+        assert_eq!(response.get_all(SET_COOKIE).iter().count(), 2);
+        assert_eq!(response[TITLE], "\"New\"");
+    }
+
+    #[test]
+    fn test_plain_value() {
+        assert_eq!(plain_value(LOCATION, "/path").unwrap(), "/path");
+        assert!(matches!(
+            plain_value(LOCATION, "/path\n"),
+            Err(Error::InvalidHeaderValue { header: LOCATION })
+        ));
+    }
+}
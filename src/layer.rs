@@ -0,0 +1,73 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::LayerMode;
+
+/// The size of an overlay
+///
+/// See <https://unpoly.com/customizing-overlays#overlay-sizes>
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerSize {
+    SMALL,
+    MEDIUM,
+    LARGE,
+    /// Grow with the content
+    GROW,
+    /// Cover the entire screen
+    FULL,
+}
+
+/// The position of a drawer or popup
+///
+/// See <https://unpoly.com/customizing-overlays#overlay-positions>
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerPosition {
+    TOP,
+    RIGHT,
+    BOTTOM,
+    LEFT,
+}
+
+/// Options for the overlay opened via `Unpoly::open_layer()`
+///
+/// Unset options use the Unpoly defaults.
+///
+/// See <https://unpoly.com/X-Up-Open-Layer> and <https://unpoly.com/up.layer.open>
+#[derive(Debug, Default, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerOptions {
+    /// The mode of the overlay, by default a modal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<LayerMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<LayerSize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<LayerPosition>,
+    /// A CSS class for the overlay element
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    /// Whether the overlay can be dismissed by the user (with a close button, escape key or clicking outside)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dismissable: Option<bool>,
+    /// Whether the overlay updates the browser location and title
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history: Option<bool>,
+    /// The context of the overlay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<Value>,
+    /// The selector of the fragment to show in the overlay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl LayerOptions {
+    /// Options for an overlay with the given mode
+    pub fn new(mode: LayerMode) -> Self {
+        LayerOptions {
+            mode: Some(mode),
+            ..Default::default()
+        }
+    }
+}
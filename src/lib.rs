@@ -1,23 +1,109 @@
+#[cfg(feature = "actix")]
+mod actix;
+#[cfg(feature = "askama")]
+mod askama;
 #[cfg(feature = "axum")]
 mod axum;
+mod cache;
+mod event;
+#[cfg(feature = "fragment")]
+mod fragment;
 mod headers;
+mod layer;
+#[cfg(feature = "axum")]
+mod method_override;
+#[cfg(feature = "axum")]
+mod middleware;
+#[cfg(feature = "poem")]
+mod poem;
+mod redirect;
+#[cfg(feature = "rocket")]
+mod rocket;
+mod target;
+mod version;
+#[cfg(feature = "warp")]
+mod warp;
+#[cfg(feature = "askama")]
+pub use crate::askama::UpTemplate;
+#[cfg(feature = "axum")]
+pub use crate::axum::UpResponse;
+#[cfg(feature = "warp")]
+pub use crate::warp::{strict_unpoly_filter, unpoly_filter};
+/// Used by the `up_template!` macro
+#[cfg(feature = "askama")]
+#[doc(hidden)]
+pub use ::askama as __askama;
+pub use cache::CachePattern;
+pub use event::{EventOptions, UpEvent};
+#[cfg(feature = "fragment")]
+pub use fragment::extract_fragments;
+pub use layer::{LayerOptions, LayerPosition, LayerSize};
+#[cfg(feature = "axum")]
+pub use method_override::{MethodOverride, MethodOverrideLayer};
+#[cfg(feature = "axum")]
+pub use middleware::{UnpolyHandle, UnpolyLayer, UnpolyService};
+pub use semver::Version;
 use std::collections::HashSet;
+use std::time::SystemTime;
+pub use target::{Placement, Target, TargetPart};
+#[cfg(feature = "derive")]
+pub use unpoly_derive::UpEvent;
+pub use version::ProtocolFeature;
 
-use derive_more::{Display, From};
-use http::HeaderMap;
-use serde::{Deserialize, Serialize};
+use derive_more::{Deref, DerefMut, Display, From};
+use http::{request::Parts, HeaderMap, HeaderValue, Method};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-#[derive(Debug, From, Display)]
+#[derive(Debug, From, Display, derive_more::Error)]
 pub enum Error {
     #[from]
-    InvalidJson(serde_json::Error),
+    InvalidJson(#[error(source)] serde_json::Error),
     EventIsNotSerializableAsObject,
+    ContextIsNotSerializableAsObject,
+    /// A request header is not valid UTF-8
+    #[display("Header {header} is not valid UTF-8")]
+    InvalidHeaderEncoding {
+        header: &'static str,
+    },
+    /// A request header does not contain valid JSON
+    #[display("Header {header} does not contain valid JSON: {source}")]
+    InvalidHeaderJson {
+        header: &'static str,
+        #[error(source)]
+        source: serde_json::Error,
+    },
+    /// A response header value contains characters which are not allowed in a header
+    #[display("Value for header {header} contains invalid characters")]
+    InvalidHeaderValue {
+        header: &'static str,
+    },
+    /// A request header does not contain a known layer mode
+    #[display("Header {header} does not contain a known layer mode: {value:?}")]
+    InvalidHeaderLayerMode {
+        header: &'static str,
+        value: String,
+    },
+    /// A request header does not contain a valid version
+    #[display("Header {header} does not contain a valid version: {value:?}")]
+    InvalidHeaderVersion {
+        header: &'static str,
+        value: String,
+    },
+    /// A request header does not contain a valid time
+    #[display("Header {header} does not contain a valid time: {value:?}")]
+    InvalidHeaderTime {
+        header: &'static str,
+        value: String,
+    },
+    /// A cache pattern is empty, `false` or contains whitespace or non-ASCII characters
+    #[display("Invalid cache pattern: {_0:?}")]
+    InvalidCachePattern(#[error(not(source))] String),
 }
 
 /// The mode of a layer
 ///
 /// See <https://unpoly.com/layer-terminology>
-#[derive(Debug, Serialize, Deserialize, Default, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum LayerMode {
     #[default]
@@ -78,6 +164,8 @@ pub enum MatchingLayer {
 /// Typical usages:
 ///
 ///```
+/// # #[cfg(feature = "axum")]
+/// # mod examples {
 /// use axum::response::IntoResponse;
 /// use axum::extract;
 /// use serde::Deserialize;
@@ -87,8 +175,8 @@ pub enum MatchingLayer {
 /// /// https://unpoly.com/optimizing-responses#omitting-content-that-isnt-targeted
 /// fn handler_target(mut unpoly: unpoly::Unpoly) -> impl IntoResponse {
 ///     let target = unpoly.target();
-///     let html = todo!("render content for target only");
-///     (unpoly.get_headers().unwrap(), html)
+///     let html: String = todo!("render content for target only");
+///     (unpoly, html)
 /// }
 ///
 /// ///  Rendering different content for overlays
@@ -96,8 +184,8 @@ pub enum MatchingLayer {
 /// fn handler_mode_target(mut unpoly: unpoly::Unpoly) -> impl IntoResponse {
 ///     let mode = unpoly.mode();
 ///     let target = unpoly.target();
-///     let html = todo!("render content for target in mode only");
-///     (unpoly.get_headers().unwrap(), html)
+///     let html: String = todo!("render content for target in mode only");
+///     (unpoly, html)
 /// }
 ///
 /// /// Rendering different content for unpoly requests
@@ -108,22 +196,24 @@ pub enum MatchingLayer {
 ///     } else {
 ///         todo!("render for full page load")
 ///     };
-///     (unpoly.get_headers().unwrap(), html)
+///     (unpoly, html)
 /// }
 ///
 /// /// Rendering content that depends on layer context
 /// /// https://unpoly.com/optimizing-responses#rendering-content-that-depends-on-layer-context
 /// fn handler_context(mut unpoly: unpoly::Unpoly) -> impl IntoResponse {
 ///     let context = unpoly.context();
-///     let html = todo!("render html for context");
-///     (unpoly.get_headers().unwrap(), html)
+///     // or deserialized into a struct
+///     let context: Option<serde_json::Map<String, serde_json::Value>> = unpoly.context_as().unwrap();
+///     let html: String = todo!("render html for context");
+///     (unpoly, html)
 /// }
 ///
 /// /// Set the title of the page via a fragment update
 /// fn handler_title(mut unpoly: unpoly::Unpoly) -> impl IntoResponse {
 ///     unpoly.set_title("My App");
-///     let html = todo!();
-///     (unpoly.get_headers().unwrap(), html)
+///     let html: String = todo!();
+///     (unpoly, html)
 /// }
 ///
 /// /// Send events to the frontend
@@ -131,15 +221,32 @@ pub enum MatchingLayer {
 ///     unpoly.emit_event("user:created", json!({"id": 152}));
 ///     // or for a specific layer
 ///     unpoly.emit_event_layer("user:created", json!({"id": 152}), unpoly::MatchingLayer::CURRENT);
-///     let html = todo!();
-///     (unpoly.get_headers().unwrap(), html)
+///     let html: String = todo!();
+///     (unpoly, html)
+/// }
+///
+/// /// Close an overlay without rendering the response
+/// /// https://unpoly.com/closing-overlays#closing-from-the-server
+/// fn handler_close_overlay(mut unpoly: unpoly::Unpoly) -> impl IntoResponse {
+///     unpoly.accept_layer(json!({"id": 152})).unwrap();
+///     unpoly.render_nothing();
+///     (unpoly, "")
+/// }
+///
+/// /// Open the response in a modal, e.g. when a login is required
+/// fn handler_login_required(mut unpoly: unpoly::Unpoly) -> impl IntoResponse {
+///     unpoly.open_layer(unpoly::LayerOptions::new(unpoly::LayerMode::MODAL));
+///     let html: String = todo!("render login form");
+///     (unpoly, html)
 /// }
 ///
 /// /// Expire cache
 /// fn handler_cache(mut unpoly: unpoly::Unpoly) -> impl IntoResponse {
-///     unpoly.set_expire_cache("/path/to/expire/*");
-///     let html = todo!();
-///     (unpoly.get_headers().unwrap(), html)
+///     unpoly.expire_cache("/path/to/expire/*").unwrap();
+///     // or prevent expiring the cache after a non-GET request
+///     unpoly.keep_cache();
+///     let html: String = todo!();
+///     (unpoly, html)
 /// }
 ///
 ///
@@ -153,42 +260,153 @@ pub enum MatchingLayer {
 /// fn handler_validate(mut unpoly: unpoly::Unpoly, extract::Form(form): extract::Form<SampleForm>) -> impl IntoResponse {
 ///     if !unpoly.validate().is_empty() {
 ///         todo!("Validate form");
-///         let html = todo!("render form with optional errors");
-///         (unpoly.get_headers().unwrap(), html)
+///         let html: String = todo!("render form with optional errors");
+///         (unpoly, html)
 ///     } else {
 ///         todo!("Process form");
-///         let html = todo!("render form with optional errors");
-///         (unpoly.get_headers().unwrap(), html)
+///         let html: String = todo!("render form with optional errors");
+///         (unpoly, html)
 ///     }
 /// }
+/// # }
 /// ```
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Unpoly {
     success: Option<bool>,
     request_version: Option<String>,
+    request_semver: Option<Version>,
     request_context: Option<serde_json::Value>,
     request_fail_context: Option<serde_json::Value>,
-    request_fail_mode: LayerMode,
-    request_mode: LayerMode,
+    request_fail_mode: Option<LayerMode>,
+    request_mode: Option<LayerMode>,
+    request_origin_mode: Option<LayerMode>,
     request_target: Option<String>,
     request_fail_target: Option<String>,
     request_validate: Vec<String>,
+    request_reload_from_time: Option<SystemTime>,
+    request_method: Option<Method>,
+    request_original_method: Option<Method>,
+    request_method_cookie: bool,
     response_context: Option<serde_json::Value>,
+    response_context_replacement: Option<Map<String, Value>>,
+    response_context_changes: Map<String, Value>,
     response_accept_layer: Option<serde_json::Value>,
     response_dismiss_layer: Option<serde_json::Value>,
+    response_open_layer: Option<LayerOptions>,
     response_events: Vec<serde_json::Value>,
-    response_evict_cache: Option<String>,
-    response_expire_cache: Option<String>,
+    response_evict_cache: Vec<CachePattern>,
+    response_expire_cache: Vec<CachePattern>,
+    response_keep_cache: bool,
     response_location: Option<String>,
     response_method: Option<String>,
     response_target: Option<String>,
     response_title: Option<String>,
     response_vary: HashSet<String>,
+    /// The handle of `UnpolyLayer` which this object was extracted from, and is written back to when dropped
+    #[cfg(feature = "axum")]
+    handle: Option<UnpolyHandle>,
 }
 
-use serde_json::Value;
+use serde_json::{Map, Value};
 
 impl Unpoly {
+    /// Create an Unpoly object from the request headers
+    ///
+    /// This is framework agnostic, so it can be used with any framework built on the `http` crate.
+    ///
+    /// Malformed headers are parsed leniently: they are ignored, or in case of invalid JSON, interpreted as `null`.
+    /// Use `try_from_headers()` to get an error instead.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Self::parse(headers, false).expect("lenient parsing does not fail")
+    }
+
+    /// Create an Unpoly object from the request headers, returning an error for malformed headers
+    pub fn try_from_headers(headers: &HeaderMap) -> Result<Self, Error> {
+        Self::parse(headers, true)
+    }
+
+    /// Create an Unpoly object from the parts of a request
+    ///
+    /// When the request is the target of a redirect created with `redirect_location()`, the Unpoly request state
+    /// is read from the `_up_*` query parameters and the location and method of the request are set as
+    /// `X-Up-Location` and `X-Up-Method`, so the Unpoly client knows the final URL and method.
+    pub fn from_request_parts(parts: &Parts) -> Self {
+        Self::parse_parts(parts, false).expect("lenient parsing does not fail")
+    }
+
+    /// Create an Unpoly object from the parts of a request, returning an error for malformed headers
+    pub fn try_from_request_parts(parts: &Parts) -> Result<Self, Error> {
+        Self::parse_parts(parts, true)
+    }
+
+    fn parse_parts(parts: &Parts, strict: bool) -> Result<Self, Error> {
+        let mut unpoly = match redirect::redirected_request(&parts.uri, &parts.headers) {
+            None => Self::parse(&parts.headers, strict)?,
+            Some((headers, location)) => {
+                let mut unpoly = Self::parse(&headers, strict)?;
+                unpoly.set_location(location);
+                unpoly.set_method(parts.method.as_str());
+                unpoly
+            }
+        };
+        if let Some(OriginalMethod(original)) = parts.extensions.get::<OriginalMethod>() {
+            // Tell the client which method the overridden request was handled as
+            if unpoly.response_method.is_none() {
+                unpoly.set_method(parts.method.as_str());
+            }
+            unpoly.request_original_method = Some(original.clone());
+        }
+        unpoly.request_method = Some(parts.method.clone());
+        Ok(unpoly)
+    }
+
+    /// Returns the method of the request, which is known when created via `from_request_parts()`
+    ///
+    /// This is the overridden method when `MethodOverrideLayer` is used.
+    pub fn request_method(&self) -> Option<&Method> {
+        self.request_method.as_ref()
+    }
+
+    /// Returns the method of the request before it was overridden by `MethodOverrideLayer`
+    pub fn original_request_method(&self) -> Option<&Method> {
+        self.request_original_method
+            .as_ref()
+            .or(self.request_method.as_ref())
+    }
+
+    /// Set the method of the request, which is known when created via `from_request_parts()`
+    ///
+    /// The method is used to set the `_up_method` cookie, see `get_headers()`.
+    pub fn set_request_method(&mut self, method: Method) {
+        self.request_method = Some(method);
+    }
+
+    // No struct update syntax, since `Unpoly` implements `Drop` with the axum feature
+    #[allow(clippy::field_reassign_with_default)]
+    fn parse(headers: &HeaderMap, strict: bool) -> Result<Self, Error> {
+        let parser = headers::HeaderParser { headers, strict };
+        let request_semver = parser.version(headers::VERSION)?;
+        let unpoly_2 = request_semver.as_ref().is_some_and(|v| v.major == 2);
+        let mut unpoly = Unpoly::default();
+        unpoly.request_version = parser.string(headers::VERSION)?;
+        unpoly.request_semver = request_semver;
+        unpoly.request_context = parser.json(headers::CONTEXT)?;
+        unpoly.request_fail_context = parser.json(headers::FAIL_CONTEXT)?;
+        unpoly.request_mode = parser.mode(headers::MODE)?;
+        unpoly.request_fail_mode = parser.mode(headers::FAIL_MODE)?;
+        unpoly.request_origin_mode = parser.mode(headers::ORIGIN_MODE)?;
+        unpoly.request_target = parser.string(headers::TARGET)?;
+        unpoly.request_fail_target = parser.string(headers::FAIL_TARGET)?;
+        unpoly.request_validate = parser.list(headers::VALIDATE)?;
+        unpoly.request_reload_from_time = if unpoly_2 {
+            parser.unix_time(headers::RELOAD_FROM_TIME)?
+        } else {
+            parser.http_date(headers::IF_MODIFIED_SINCE)?
+        };
+        unpoly.request_method_cookie = parser.has_cookie(headers::METHOD_COOKIE);
+        Ok(unpoly)
+    }
+
     /// Returns true if the request is from an Unpoly client
     ///
     /// A request is from an Unpoly client if the `X-Up-Version` header is present
@@ -201,6 +419,54 @@ impl Unpoly {
         }
     }
 
+    /// Returns the version of the Unpoly client, if the `X-Up-Version` header is present and valid
+    pub fn version(&mut self) -> Option<&Version> {
+        if self.request_semver.is_some() {
+            self.response_vary.insert("X-Up-Version".to_string());
+        }
+        self.request_semver.as_ref()
+    }
+
+    /// Returns true if the Unpoly client supports the given feature of the protocol
+    ///
+    /// When the version of the client is not known, the latest protocol is assumed.
+    pub fn supports(&mut self, feature: ProtocolFeature) -> bool {
+        self.version()
+            .is_none_or(|version| feature.is_supported_by(version))
+    }
+
+    /// Same as `supports()`, without adding `X-Up-Version` to the `Vary` header
+    fn client_supports(&self, feature: ProtocolFeature) -> bool {
+        self.request_semver
+            .as_ref()
+            .is_none_or(|version| feature.is_supported_by(version))
+    }
+
+    /// Returns true if the client uses the Unpoly 2 protocol, which has its own headers for reloading and the cache
+    fn is_unpoly_2(&self) -> bool {
+        self.request_semver.as_ref().is_some_and(|v| v.major == 2)
+    }
+
+    /// Returns the `Set-Cookie` value for the `_up_method` cookie, if it must be set or deleted
+    fn method_cookie(&self) -> Option<String> {
+        match &self.request_method {
+            Some(method)
+                if method != Method::GET
+                    && method != Method::HEAD
+                    && self.request_version.is_none() =>
+            {
+                Some(format!("{}={}; Path=/", headers::METHOD_COOKIE, method))
+            }
+            // Without a known method, a non-GET full page load cannot be ruled out
+            _ if self.request_method_cookie
+                && (self.request_method.is_some() || self.request_version.is_some()) =>
+            {
+                Some(format!("{}=; Path=/; Max-Age=0", headers::METHOD_COOKIE))
+            }
+            _ => None,
+        }
+    }
+
     /// Returns:
     /// - Some(true) if we handle a success case
     /// - Some(false) if we handle a failure case
@@ -231,65 +497,117 @@ impl Unpoly {
     pub fn mode(&mut self) -> &LayerMode {
         if let Some(false) = self.success {
             self.response_vary.insert("X-Up-Fail-Mode".to_string());
-            &self.request_fail_mode
+            self.request_fail_mode.as_ref().unwrap_or(&LayerMode::ROOT)
         } else {
             self.response_vary.insert("X-Up-Mode".to_string());
-            &self.request_mode
+            self.request_mode.as_ref().unwrap_or(&LayerMode::ROOT)
         }
     }
 
+    /// Returns the mode of the layer from which the request originated
+    ///
+    /// E.g. when a link in a modal targets the root layer, `mode()` is the root layer and `origin_mode()` is
+    /// the modal layer.
+    ///
+    /// See <https://unpoly.com/X-Up-Origin-Mode>
+    pub fn origin_mode(&mut self) -> &LayerMode {
+        self.response_vary.insert("X-Up-Origin-Mode".to_string());
+        self.request_origin_mode
+            .as_ref()
+            .unwrap_or(&LayerMode::ROOT)
+    }
+
+    /// Emit an event on the given layer
     pub fn emit_event_layer<S: Serialize>(
         &mut self,
         type_: impl Into<String>,
         event: S,
         matching_layer: MatchingLayer,
     ) -> Result<(), Error> {
-        let mut event = serde_json::to_value(event)?;
-        if !event.is_object() {
-            return Err(Error::EventIsNotSerializableAsObject);
-        }
+        self.emit_event_with(type_, event, EventOptions::new().layer(matching_layer))
+    }
 
-        event.as_object_mut().unwrap().insert(
-            "layer".to_string(),
-            match matching_layer {
-                MatchingLayer::INDEX(index) => Value::Number(index.into()),
-                other => serde_json::to_value(other).unwrap(),
-            },
-        );
+    /// Emit a typed event, see `UpEvent`
+    pub fn emit<E: UpEvent>(&mut self, event: E) -> Result<(), Error> {
+        self.emit_event(E::TYPE, event)
+    }
 
-        self.emit_event(type_, event)?;
-        Ok(())
+    /// Emit a typed event with the given options, see `UpEvent`
+    pub fn emit_with<E: UpEvent>(&mut self, event: E, options: EventOptions) -> Result<(), Error> {
+        self.emit_event_with(E::TYPE, event, options)
     }
 
+    /// Accept the current overlay with the given value
+    ///
+    /// See <https://unpoly.com/X-Up-Accept-Layer>
     pub fn accept_layer<S: Serialize>(&mut self, value: S) -> Result<(), Error> {
         self.response_accept_layer = Some(serde_json::to_value(value)?);
         self.response_dismiss_layer = None;
         Ok(())
     }
 
+    /// Accept the current overlay without a value (the overlay's result value will be `null`)
     pub fn accept_layer_without_value(&mut self) -> Result<(), Error> {
-        self.accept_layer("null")?;
-        Ok(())
+        self.accept_layer(Value::Null)
+    }
+
+    /// Returns the value with which the overlay is accepted, if `accept_layer()` is called
+    pub fn accepted_value(&self) -> Option<&Value> {
+        self.response_accept_layer.as_ref()
     }
 
+    /// Dismiss the current overlay with the given value
+    ///
+    /// See <https://unpoly.com/X-Up-Dismiss-Layer>
     pub fn dismiss_layer<S: Serialize>(&mut self, value: S) -> Result<(), Error> {
-        self.response_dismiss_layer = Some(serde_json::to_value(value).unwrap());
+        self.response_dismiss_layer = Some(serde_json::to_value(value)?);
         self.response_accept_layer = None;
         Ok(())
     }
 
+    /// Dismiss the current overlay without a value (the overlay's result value will be `null`)
     pub fn dismiss_layer_without_value(&mut self) -> Result<(), Error> {
-        self.dismiss_layer("null")?;
-        Ok(())
+        self.dismiss_layer(Value::Null)
+    }
+
+    /// Returns the value with which the overlay is dismissed, if `dismiss_layer()` is called
+    pub fn dismissed_value(&self) -> Option<&Value> {
+        self.response_dismiss_layer.as_ref()
+    }
+
+    /// Open the response in a new overlay with the given options
+    ///
+    /// E.g. to show a login form in a modal when a login is required.
+    ///
+    /// See <https://unpoly.com/X-Up-Open-Layer>
+    pub fn open_layer(&mut self, options: LayerOptions) {
+        self.response_open_layer = Some(options);
+    }
+
+    /// Returns the options of the overlay to open, if `open_layer()` is called
+    pub fn opened_layer(&self) -> Option<&LayerOptions> {
+        self.response_open_layer.as_ref()
+    }
+
+    /// Tell Unpoly to not render the response, by setting `X-Up-Target` to `:none`
+    ///
+    /// This is typically combined with `accept_layer()` or `dismiss_layer()`, so the handler can return an
+    /// empty body when closing an overlay.
+    ///
+    /// See <https://unpoly.com/X-Up-Target>
+    pub fn render_nothing(&mut self) {
+        self.set_target(":none");
     }
 
-    /// Get the X-Up-Context response header when set (via `set_context()``), or the X-Up-[Fail-]Context request header
-    /// when the response header is not set
+    /// Get the context of the layer
+    ///
+    /// This is the X-Up-[Fail-]Context request header with the changes made via `set_context()`,
+    /// `context_insert()` and `context_remove()` applied.
     pub fn context(&mut self) -> Option<&Value> {
-        if self.response_context.is_some() {
-            return self.response_context.as_ref();
+        if let Some(context) = self.response_context_object() {
+            self.response_context = Some(Value::Object(context));
         }
-        if Some(false) == self.success {
+        let request_context = if Some(false) == self.success {
             if self.request_fail_context.is_some() && self.is_up() {
                 self.response_vary.insert("X-Up-Fail-Context".to_string());
             }
@@ -299,11 +617,106 @@ impl Unpoly {
                 self.response_vary.insert("X-Up-Context".to_string());
             }
             self.request_context.as_ref()
+        };
+        self.response_context.as_ref().or(request_context)
+    }
+
+    /// Get the context (see `context()`) deserialized into `T`
+    ///
+    /// Returns `Ok(None)` when there is no context and an error when the context cannot be deserialized into `T`.
+    pub fn context_as<T: DeserializeOwned>(&mut self) -> Result<Option<T>, Error> {
+        Ok(self
+            .context()
+            .map(|context| T::deserialize(context))
+            .transpose()?)
+    }
+
+    /// Replace the context of the layer
+    ///
+    /// Only the keys which differ from the request context are sent in the X-Up-Context response header;
+    /// keys which are no longer present are sent as `null`, so Unpoly removes them.
+    ///
+    /// Returns an error when the context cannot be serialized into a JSON object.
+    pub fn set_context<S: Serialize>(&mut self, layer: S) -> Result<(), Error> {
+        let Value::Object(context) = serde_json::to_value(layer)? else {
+            return Err(Error::ContextIsNotSerializableAsObject);
+        };
+        self.response_context_replacement = Some(context);
+        self.response_context_changes.clear();
+        Ok(())
+    }
+
+    /// Set a single key of the context of the layer
+    ///
+    /// Setting a key to `null` removes it, like `context_remove()`.
+    pub fn context_insert<S: Serialize>(
+        &mut self,
+        key: impl Into<String>,
+        value: S,
+    ) -> Result<(), Error> {
+        let value = serde_json::to_value(value)?;
+        self.response_context_changes.insert(key.into(), value);
+        Ok(())
+    }
+
+    /// Remove a single key from the context of the layer
+    pub fn context_remove(&mut self, key: impl Into<String>) {
+        self.response_context_changes
+            .insert(key.into(), Value::Null);
+    }
+
+    /// The request context of the layer to update, which depends on the success status
+    fn request_context_object(&self) -> Map<String, Value> {
+        let context = if Some(false) == self.success {
+            &self.request_fail_context
+        } else {
+            &self.request_context
+        };
+        match context {
+            Some(Value::Object(context)) => context.clone(),
+            _ => Map::new(),
         }
     }
 
-    pub fn set_context<S: Serialize>(&mut self, layer: S) {
-        self.response_context = Some(serde_json::to_value(layer).unwrap());
+    /// The context with the changes applied, or `None` if the context is not changed
+    ///
+    /// The changes are applied to the request context for the final success status, so they can be made before
+    /// `set_success()` is called.
+    fn response_context_object(&self) -> Option<Map<String, Value>> {
+        if self.response_context_replacement.is_none() && self.response_context_changes.is_empty() {
+            return None;
+        }
+        let mut context = self
+            .response_context_replacement
+            .clone()
+            .unwrap_or_else(|| self.request_context_object());
+        for (key, value) in &self.response_context_changes {
+            if value.is_null() {
+                context.remove(key);
+            } else {
+                context.insert(key.clone(), value.clone());
+            }
+        }
+        Some(context)
+    }
+
+    /// The keys of the context which differ from the request context, with removed keys as `null`
+    fn response_context_diff(&self) -> Map<String, Value> {
+        let Some(context) = self.response_context_object() else {
+            return Map::new();
+        };
+        let request_context = self.request_context_object();
+        request_context
+            .keys()
+            .filter(|key| !context.contains_key(*key))
+            .map(|key| (key.clone(), Value::Null))
+            .chain(
+                context
+                    .iter()
+                    .filter(|(key, value)| request_context.get(*key) != Some(value))
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            )
+            .collect()
     }
 
     pub fn target(&mut self) -> Option<&str> {
@@ -319,6 +732,23 @@ impl Unpoly {
         }
     }
 
+    /// Returns the target (see `target()`) parsed into its selectors
+    pub fn parsed_target(&mut self) -> Option<Target> {
+        self.target().map(Target::parse)
+    }
+
+    /// Returns the parsed target of a response with the given status, i.e. the fail target for an error status
+    ///
+    /// A target or success set by the handler takes precedence over the status.
+    #[cfg(all(feature = "fragment", feature = "axum"))]
+    pub(crate) fn response_target(&mut self, success: bool) -> Option<Target> {
+        if success || self.success.is_some() || self.response_target.is_some() {
+            return self.parsed_target();
+        }
+        self.response_vary.insert("X-Up-Fail-Target".to_string());
+        self.request_fail_target.as_deref().map(Target::parse)
+    }
+
     pub fn set_target(&mut self, target: impl Into<String>) {
         self.response_target = Some(target.into());
     }
@@ -330,6 +760,64 @@ impl Unpoly {
         &self.request_validate
     }
 
+    /// Returns the time of the cached response which Unpoly is reloading
+    ///
+    /// Unpoly 3 sends this as `If-Modified-Since`, Unpoly 2 as `X-Up-Reload-From-Time`.
+    ///
+    /// See <https://unpoly.com/conditional-requests>
+    pub fn reload_from_time(&mut self) -> Option<SystemTime> {
+        if self.request_reload_from_time.is_some() && self.is_unpoly_2() {
+            self.response_vary
+                .insert("X-Up-Reload-From-Time".to_string());
+        }
+        self.request_reload_from_time
+    }
+
+    /// Returns the location to redirect to, with the Unpoly request state added as `_up_*` query parameters
+    ///
+    /// The Unpoly object of the redirect target (see `from_request_parts()`) picks up this state and sets
+    /// `X-Up-Location` and `X-Up-Method`, so the Unpoly client learns the final URL and method.
+    /// For requests which are not from an Unpoly client, the location is returned unchanged.
+    pub fn redirect_location(&self, location: impl Into<String>) -> String {
+        let location = location.into();
+        if self.request_version.is_none() {
+            return location;
+        }
+        let mode = |mode: &LayerMode| {
+            serde_json::to_value(mode)
+                .ok()
+                .and_then(|mode| mode.as_str().map(str::to_string))
+        };
+        let values = [
+            (headers::VERSION, self.request_version.clone()),
+            (headers::TARGET, self.request_target.clone()),
+            (headers::FAIL_TARGET, self.request_fail_target.clone()),
+            (headers::MODE, self.request_mode.as_ref().and_then(mode)),
+            (
+                headers::FAIL_MODE,
+                self.request_fail_mode.as_ref().and_then(mode),
+            ),
+            (
+                headers::ORIGIN_MODE,
+                self.request_origin_mode.as_ref().and_then(mode),
+            ),
+            (
+                headers::CONTEXT,
+                self.request_context.as_ref().map(Value::to_string),
+            ),
+            (
+                headers::FAIL_CONTEXT,
+                self.request_fail_context.as_ref().map(Value::to_string),
+            ),
+        ];
+        redirect::append_params(
+            &location,
+            values
+                .into_iter()
+                .filter_map(|(header, value)| Some((header, value?))),
+        )
+    }
+
     pub fn title(&self) -> Option<&str> {
         self.response_title.as_deref()
     }
@@ -354,89 +842,808 @@ impl Unpoly {
         self.response_method = Some(method.into());
     }
 
+    /// Emit an event on the current layer
+    ///
+    /// The event must be serializable as JSON object.
+    ///
+    /// See <https://unpoly.com/X-Up-Events>
     pub fn emit_event<S: Serialize>(
         &mut self,
         type_: impl Into<String>,
         event: S,
     ) -> Result<(), Error> {
-        let mut event = serde_json::to_value(event)?;
-        if !event.is_object() {
+        self.emit_event_with(type_, event, EventOptions::new())
+    }
+
+    /// Emit an event with the given options
+    pub fn emit_event_with<S: Serialize>(
+        &mut self,
+        type_: impl Into<String>,
+        event: S,
+        options: EventOptions,
+    ) -> Result<(), Error> {
+        let Value::Object(mut event) = serde_json::to_value(event)? else {
             return Err(Error::EventIsNotSerializableAsObject);
-        }
+        };
 
-        let type_: String = type_.into();
+        options.apply(&mut event)?;
+        event.insert("type".to_string(), Value::String(type_.into()));
 
-        event
-            .as_object_mut()
-            .unwrap()
-            .insert("type".to_string(), Value::String(type_));
+        self.response_events.push(Value::Object(event));
+        Ok(())
+    }
 
-        self.response_events.push(event);
+    /// Evict the cached responses matching the pattern, in addition to previously evicted patterns
+    ///
+    /// Unpoly 2 clients receive the evicted and expired patterns together as `X-Up-Clear-Cache`.
+    ///
+    /// See <https://unpoly.com/X-Up-Evict-Cache>
+    pub fn evict_cache(&mut self, pattern: impl Into<String>) -> Result<(), Error> {
+        self.response_evict_cache.push(CachePattern::new(pattern)?);
+        Ok(())
+    }
+
+    /// Returns the patterns of the cached responses to evict
+    pub fn evict_cache_patterns(&self) -> &[CachePattern] {
+        &self.response_evict_cache
+    }
+
+    /// Expire the cached responses matching the pattern, in addition to previously expired patterns
+    ///
+    /// Unpoly 2 clients receive the expired and evicted patterns together as `X-Up-Clear-Cache`.
+    ///
+    /// See <https://unpoly.com/X-Up-Expire-Cache>
+    pub fn expire_cache(&mut self, pattern: impl Into<String>) -> Result<(), Error> {
+        self.response_expire_cache.push(CachePattern::new(pattern)?);
+        self.response_keep_cache = false;
         Ok(())
     }
 
+    /// Returns the patterns of the cached responses to expire
+    pub fn expire_cache_patterns(&self) -> &[CachePattern] {
+        &self.response_expire_cache
+    }
+
+    /// Prevent Unpoly from expiring the cache, which it does by default after a non-GET request
+    ///
+    /// This sends `X-Up-Expire-Cache: false` (`X-Up-Clear-Cache: false` to Unpoly 2 clients) and discards the patterns
+    /// given to `expire_cache()`. The `X-Up-Cache` header of Unpoly 2 is not supported.
+    pub fn keep_cache(&mut self) {
+        self.response_expire_cache.clear();
+        self.response_keep_cache = true;
+    }
+
+    /// Returns true if `keep_cache()` is called
+    pub fn keeps_cache(&self) -> bool {
+        self.response_keep_cache
+    }
+
+    /// Replace the patterns of the cached responses to evict with a single unchecked pattern
     pub fn set_evict_cache(&mut self, cache: impl Into<String>) {
-        self.response_evict_cache = Some(cache.into());
+        self.response_evict_cache = vec![CachePattern::new_unchecked(cache)];
     }
 
+    /// Replace the patterns of the cached responses to expire with a single unchecked pattern
+    ///
+    /// `false` keeps the cache, like `keep_cache()`.
     pub fn set_expire_cache(&mut self, cache: impl Into<String>) {
-        self.response_expire_cache = Some(cache.into());
+        let cache = cache.into();
+        if cache == "false" {
+            self.keep_cache();
+        } else {
+            self.response_expire_cache = vec![CachePattern::new_unchecked(cache)];
+            self.response_keep_cache = false;
+        }
+    }
+
+    /// Add the Unpoly response headers (see `get_headers()`) to the headers of a response
+    ///
+    /// The `Vary` header is combined with an existing `Vary` header and cookies are appended, while other headers
+    /// are replaced.
+    pub fn apply_headers(&self, response: &mut HeaderMap) -> Result<(), Error> {
+        headers::merge_headers(response, self.get_headers()?);
+        Ok(())
     }
 
+    /// Returns the Unpoly response headers
+    ///
+    /// JSON values (like events and context) are encoded with non-ASCII characters escaped and the title is
+    /// encoded as JSON string. Returns an error when a value cannot be encoded, e.g. a location with a newline.
+    ///
+    /// When a non-GET request results in a full page load, the `_up_method` cookie is set, so the Unpoly client
+    /// knows that the page cannot be reloaded with a GET request. Otherwise a present `_up_method` cookie is deleted,
+    /// unless the method of a full page load is unknown, see `set_request_method()`.
+    /// See <https://unpoly.com/up.protocol>
     pub fn get_headers(&self) -> Result<HeaderMap, Error> {
         let mut headers = HeaderMap::new();
+        let mut vary = self.response_vary.clone();
         if let Some(title) = &self.response_title {
-            headers.insert(headers::TITLE, title.parse().unwrap());
+            // Since Unpoly 3 the title is JSON encoded
+            if self.request_semver.is_some() {
+                vary.insert("X-Up-Version".to_string());
+            }
+            if self.client_supports(ProtocolFeature::JsonTitle) {
+                headers.insert(headers::TITLE, headers::json_value(headers::TITLE, title)?);
+            } else {
+                headers.insert(headers::TITLE, headers::plain_value(headers::TITLE, title)?);
+            }
         }
         if let Some(location) = &self.response_location {
-            headers.insert(headers::LOCATION, location.parse().unwrap());
+            headers.insert(
+                headers::LOCATION,
+                headers::plain_value(headers::LOCATION, location)?,
+            );
         }
         if let Some(accept_layer) = &self.response_accept_layer {
             headers.insert(
                 headers::ACCEPT_LAYER,
-                serde_json::to_string(accept_layer)?.parse().unwrap(),
+                headers::json_value(headers::ACCEPT_LAYER, accept_layer)?,
             );
         }
         if let Some(dismiss_layer) = &self.response_dismiss_layer {
             headers.insert(
                 headers::DISMISS_LAYER,
-                serde_json::to_string(dismiss_layer)?.parse().unwrap(),
+                headers::json_value(headers::DISMISS_LAYER, dismiss_layer)?,
             );
         }
-        if let Some(context) = &self.response_context {
+        if let Some(open_layer) = &self.response_open_layer {
+            headers.insert(
+                headers::OPEN_LAYER,
+                headers::json_value(headers::OPEN_LAYER, open_layer)?,
+            );
+        }
+        let context_diff = self.response_context_diff();
+        if !context_diff.is_empty() {
             headers.insert(
                 headers::CONTEXT,
-                serde_json::to_string(context)?.parse().unwrap(),
+                headers::json_value(headers::CONTEXT, &context_diff)?,
             );
         }
         if let Some(target) = &self.response_target {
-            headers.insert(headers::TARGET, target.parse().unwrap());
+            headers.insert(
+                headers::TARGET,
+                headers::plain_value(headers::TARGET, target)?,
+            );
         }
         if let Some(method) = &self.response_method {
-            headers.insert(headers::METHOD, method.parse().unwrap());
+            headers.insert(
+                headers::METHOD,
+                headers::plain_value(headers::METHOD, method)?,
+            );
         }
-        if let Some(evict_cache) = &self.response_evict_cache {
-            headers.insert(headers::EVICT_CACHE, evict_cache.parse().unwrap());
+        if !self.is_unpoly_2() {
+            if !self.response_evict_cache.is_empty() {
+                headers.insert(
+                    headers::EVICT_CACHE,
+                    headers::plain_value(
+                        headers::EVICT_CACHE,
+                        &cache::join(&self.response_evict_cache),
+                    )?,
+                );
+            }
+            if self.response_keep_cache {
+                headers.insert(headers::EXPIRE_CACHE, HeaderValue::from_static("false"));
+            } else if !self.response_expire_cache.is_empty() {
+                headers.insert(
+                    headers::EXPIRE_CACHE,
+                    headers::plain_value(
+                        headers::EXPIRE_CACHE,
+                        &cache::join(&self.response_expire_cache),
+                    )?,
+                );
+            }
+        } else {
+            // Unpoly 2 does not distinguish between expiring and evicting the cache
+            let patterns = [
+                self.response_expire_cache.as_slice(),
+                self.response_evict_cache.as_slice(),
+            ]
+            .concat();
+            if !patterns.is_empty() {
+                headers.insert(
+                    headers::CLEAR_CACHE,
+                    headers::plain_value(headers::CLEAR_CACHE, &cache::join(&patterns))?,
+                );
+            } else if self.response_keep_cache {
+                headers.insert(headers::CLEAR_CACHE, HeaderValue::from_static("false"));
+            }
         }
-        if let Some(expire_cache) = &self.response_expire_cache {
-            headers.insert(headers::EXPIRE_CACHE, expire_cache.parse().unwrap());
+        if self.request_semver.is_some()
+            && (!self.response_evict_cache.is_empty()
+                || !self.response_expire_cache.is_empty()
+                || self.response_keep_cache)
+        {
+            vary.insert("X-Up-Version".to_string());
         }
         if !self.response_events.is_empty() {
-            let events = serde_json::to_value(&self.response_events)?;
             headers.insert(
                 headers::EVENTS,
-                serde_json::to_string(&events)?.parse().unwrap(),
+                headers::json_value(headers::EVENTS, &self.response_events)?,
+            );
+        }
+        if let Some(cookie) = self.method_cookie() {
+            headers.append(
+                headers::SET_COOKIE,
+                headers::plain_value(headers::SET_COOKIE, &cookie)?,
             );
         }
-        if !self.response_vary.is_empty() {
-            let mut vary: Vec<&String> = self.response_vary.iter().collect();
+        if !vary.is_empty() {
+            let mut vary: Vec<&String> = vary.iter().collect();
             vary.sort();
             let vary = vary.iter().fold(
                 "".to_string(),
                 |a, b| if !a.is_empty() { a + "," } else { a } + b,
             );
-            headers.insert(headers::VARY, vary.parse().unwrap());
+            headers.insert(headers::VARY, headers::plain_value(headers::VARY, &vary)?);
         }
         Ok(headers)
     }
 }
+
+/// Extractor for an Unpoly object which rejects requests with malformed Unpoly headers
+///
+/// The rejection is a `400 Bad Request` with a body naming the offending header.
+#[derive(Debug, Deref, DerefMut)]
+pub struct StrictUnpoly(pub Unpoly);
+
+/// The method of the request before it was overridden by `MethodOverrideLayer`
+///
+/// It is stored in the request extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OriginalMethod(pub Method);
+
+/// Responder which adds the Unpoly headers to the response of the wrapped responder
+///
+/// It implements the response traits of actix-web (`actix` feature), Rocket (`rocket` feature), poem (`poem`
+/// feature) and warp (`warp` feature). When the headers cannot be encoded, the response is replaced by a
+/// `500 Internal Server Error`.
+#[cfg(any(
+    feature = "actix",
+    feature = "poem",
+    feature = "rocket",
+    feature = "warp"
+))]
+#[derive(Debug)]
+pub struct UpResponder<R> {
+    unpoly: Unpoly,
+    responder: R,
+}
+
+#[cfg(any(
+    feature = "actix",
+    feature = "poem",
+    feature = "rocket",
+    feature = "warp"
+))]
+impl<R> UpResponder<R> {
+    pub fn new(unpoly: Unpoly, responder: R) -> Self {
+        UpResponder { unpoly, responder }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "3.0.0".parse().unwrap());
+        headers.insert(headers::CONTEXT, "{\"lives\": 42}".parse().unwrap());
+        headers.insert(headers::MODE, "modal".parse().unwrap());
+        headers.insert(headers::TARGET, ".content".parse().unwrap());
+        headers.insert(headers::VALIDATE, "name email".parse().unwrap());
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+
+        assert!(unpoly.is_up());
+        assert_eq!(unpoly.context(), Some(&serde_json::json!({"lives": 42})));
+        assert_eq!(*unpoly.mode(), LayerMode::MODAL);
+        assert_eq!(unpoly.target(), Some(".content"));
+        assert!(unpoly.parsed_target().unwrap().contains(".content"));
+        assert_eq!(unpoly.validate(), &vec!["name", "email"]);
+        assert_eq!(
+            unpoly.get_headers().unwrap()["Vary"],
+            "X-Up-Context,X-Up-Mode,X-Up-Target,X-Up-Validate,X-Up-Version".to_string()
+        );
+    }
+
+    #[test]
+    fn test_from_request_parts() {
+        let (parts, _) = http::Request::builder()
+            .header(headers::VERSION, "3.0.0")
+            .header(headers::FAIL_MODE, "drawer")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let mut unpoly = Unpoly::from_request_parts(&parts);
+        unpoly.set_success(false);
+
+        assert!(unpoly.is_up());
+        assert_eq!(*unpoly.mode(), LayerMode::DRAWER);
+    }
+
+    #[test]
+    fn test_get_headers_encoding() {
+        let mut unpoly = Unpoly::default();
+        unpoly.set_title("Caf\u{e9} \"Central\"\n");
+        unpoly
+            .emit_event("user:created", serde_json::json!({"name": "Zo\u{eb}"}))
+            .unwrap();
+
+        let headers = unpoly.get_headers().unwrap();
+        assert_eq!(headers["X-Up-Title"], "\"Caf\\u00e9 \\\"Central\\\"\\n\"");
+        assert_eq!(
+            headers["X-Up-Events"],
+            "[{\"name\":\"Zo\\u00eb\",\"type\":\"user:created\"}]"
+        );
+
+        unpoly.set_location("/caf\u{e9}\n");
+        assert!(matches!(
+            unpoly.get_headers(),
+            Err(Error::InvalidHeaderValue {
+                header: headers::LOCATION
+            })
+        ));
+    }
+
+    #[test]
+    fn test_version() {
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "2.7".parse().unwrap());
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert_eq!(unpoly.version(), Some(&Version::new(2, 7, 0)));
+        assert!(unpoly.supports(ProtocolFeature::Context));
+        assert!(!unpoly.supports(ProtocolFeature::JsonTitle));
+
+        unpoly.set_title("Hello");
+        let headers = unpoly.get_headers().unwrap();
+        assert_eq!(headers["X-Up-Title"], "Hello");
+        assert_eq!(headers["Vary"], "X-Up-Version");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "3.9.2".parse().unwrap());
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert!(unpoly.supports(ProtocolFeature::JsonTitle));
+        unpoly.set_title("Hello");
+        assert_eq!(unpoly.get_headers().unwrap()["X-Up-Title"], "\"Hello\"");
+
+        let mut unpoly = Unpoly::default();
+        assert_eq!(unpoly.version(), None);
+        assert!(unpoly.supports(ProtocolFeature::JsonTitle));
+        assert!(!unpoly.get_headers().unwrap().contains_key("Vary"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "latest".parse().unwrap());
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert!(unpoly.is_up());
+        assert_eq!(unpoly.version(), None);
+        assert!(matches!(
+            Unpoly::try_from_headers(&headers),
+            Err(Error::InvalidHeaderVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unpoly_2() {
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "2.7.2".parse().unwrap());
+        headers.insert(headers::RELOAD_FROM_TIME, "1608712106".parse().unwrap());
+        headers.insert(
+            headers::IF_MODIFIED_SINCE,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert_eq!(
+            unpoly.reload_from_time(),
+            Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1608712106))
+        );
+
+        unpoly.set_title("Hello");
+        unpoly.expire_cache("/users/*").unwrap();
+        unpoly.evict_cache("/tasks/*").unwrap();
+
+        let headers = unpoly.get_headers().unwrap();
+        assert_eq!(headers["X-Up-Title"], "Hello");
+        assert_eq!(headers["X-Up-Clear-Cache"], "/users/* /tasks/*");
+        assert!(!headers.contains_key("X-Up-Expire-Cache"));
+        assert!(!headers.contains_key("X-Up-Evict-Cache"));
+        assert_eq!(headers["Vary"], "X-Up-Reload-From-Time,X-Up-Version");
+
+        let mut unpoly = Unpoly::from_headers(&HeaderMap::from_iter([(
+            http::header::HeaderName::from_static("x-up-version"),
+            HeaderValue::from_static("2.0.0"),
+        )]));
+        unpoly.keep_cache();
+        assert_eq!(unpoly.get_headers().unwrap()["X-Up-Clear-Cache"], "false");
+    }
+
+    #[test]
+    fn test_method_cookie_unknown_method() {
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::COOKIE, "_up_method=POST".parse().unwrap());
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert!(!unpoly.get_headers().unwrap().contains_key("Set-Cookie"));
+
+        unpoly.set_request_method(Method::GET);
+        assert_eq!(
+            unpoly.get_headers().unwrap()["Set-Cookie"],
+            "_up_method=; Path=/; Max-Age=0"
+        );
+
+        headers.insert(headers::VERSION, "3.0.0".parse().unwrap());
+        assert_eq!(
+            Unpoly::from_headers(&headers).get_headers().unwrap()["Set-Cookie"],
+            "_up_method=; Path=/; Max-Age=0"
+        );
+    }
+
+    #[test]
+    fn test_unpoly_2_reload_from_time_overflow() {
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "2.7.0".parse().unwrap());
+        headers.insert(
+            headers::RELOAD_FROM_TIME,
+            "18446744073709551615".parse().unwrap(),
+        );
+
+        assert_eq!(Unpoly::from_headers(&headers).reload_from_time(), None);
+        assert!(matches!(
+            Unpoly::try_from_headers(&headers),
+            Err(Error::InvalidHeaderTime { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unpoly_1() {
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "1.0.0".parse().unwrap());
+        headers.insert(
+            headers::IF_MODIFIED_SINCE,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+
+        // The Unpoly 2 compatibility is limited to Unpoly 2 clients
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert_eq!(
+            unpoly.reload_from_time(),
+            Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1445412480))
+        );
+
+        unpoly.expire_cache("/users/*").unwrap();
+        let headers = unpoly.get_headers().unwrap();
+        assert_eq!(headers["X-Up-Expire-Cache"], "/users/*");
+        assert!(!headers.contains_key("X-Up-Clear-Cache"));
+    }
+
+    #[test]
+    fn test_reload_from_time() {
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "3.0.0".parse().unwrap());
+        headers.insert(headers::RELOAD_FROM_TIME, "1608712106".parse().unwrap());
+        headers.insert(
+            headers::IF_MODIFIED_SINCE,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert_eq!(
+            unpoly.reload_from_time(),
+            Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1445412480))
+        );
+        assert!(!unpoly.get_headers().unwrap().contains_key("Vary"));
+
+        headers.insert(headers::IF_MODIFIED_SINCE, "yesterday".parse().unwrap());
+        assert_eq!(Unpoly::from_headers(&headers).reload_from_time(), None);
+        assert!(matches!(
+            Unpoly::try_from_headers(&headers),
+            Err(Error::InvalidHeaderTime { .. })
+        ));
+    }
+
+    #[test]
+    fn test_accept_dismiss_layer() {
+        let mut unpoly = Unpoly::default();
+        unpoly.accept_layer_without_value().unwrap();
+        unpoly.render_nothing();
+
+        assert_eq!(unpoly.accepted_value(), Some(&Value::Null));
+        assert_eq!(unpoly.dismissed_value(), None);
+        let headers = unpoly.get_headers().unwrap();
+        assert_eq!(headers["X-Up-Accept-Layer"], "null");
+        assert_eq!(headers["X-Up-Target"], ":none");
+        assert!(!headers.contains_key("X-Up-Dismiss-Layer"));
+
+        unpoly
+            .dismiss_layer(serde_json::json!({"reason": "cancel"}))
+            .unwrap();
+        assert_eq!(unpoly.accepted_value(), None);
+        assert_eq!(
+            unpoly.dismissed_value(),
+            Some(&serde_json::json!({"reason": "cancel"}))
+        );
+        let headers = unpoly.get_headers().unwrap();
+        assert_eq!(headers["X-Up-Dismiss-Layer"], "{\"reason\":\"cancel\"}");
+        assert!(!headers.contains_key("X-Up-Accept-Layer"));
+
+        unpoly.dismiss_layer_without_value().unwrap();
+        assert_eq!(unpoly.get_headers().unwrap()["X-Up-Dismiss-Layer"], "null");
+    }
+
+    #[test]
+    fn test_open_layer() {
+        let mut unpoly = Unpoly::default();
+        unpoly.open_layer(LayerOptions {
+            size: Some(LayerSize::SMALL),
+            dismissable: Some(false),
+            context: Some(serde_json::json!({"next": "/checkout"})),
+            ..LayerOptions::new(LayerMode::MODAL)
+        });
+
+        assert_eq!(unpoly.opened_layer().unwrap().mode, Some(LayerMode::MODAL));
+        assert_eq!(
+            unpoly.get_headers().unwrap()["X-Up-Open-Layer"],
+            "{\"mode\":\"modal\",\"size\":\"small\",\"dismissable\":false,\"context\":{\"next\":\"/checkout\"}}"
+        );
+
+        unpoly.open_layer(LayerOptions {
+            position: Some(LayerPosition::RIGHT),
+            history: Some(true),
+            ..LayerOptions::new(LayerMode::DRAWER)
+        });
+        assert_eq!(
+            unpoly.get_headers().unwrap()["X-Up-Open-Layer"],
+            "{\"mode\":\"drawer\",\"position\":\"right\",\"history\":true}"
+        );
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut unpoly = Unpoly::default();
+        unpoly.expire_cache("/users/*").unwrap();
+        unpoly.expire_cache("/tasks").unwrap();
+        unpoly.evict_cache("*").unwrap();
+        assert!(unpoly.expire_cache("/users /tasks").is_err());
+
+        assert_eq!(
+            unpoly.expire_cache_patterns(),
+            &[
+                CachePattern::new("/users/*").unwrap(),
+                CachePattern::new("/tasks").unwrap()
+            ]
+        );
+        let headers = unpoly.get_headers().unwrap();
+        assert_eq!(headers["X-Up-Expire-Cache"], "/users/* /tasks");
+        assert_eq!(headers["X-Up-Evict-Cache"], "*");
+
+        unpoly.keep_cache();
+        assert!(unpoly.keeps_cache());
+        assert!(unpoly.expire_cache_patterns().is_empty());
+        assert_eq!(unpoly.get_headers().unwrap()["X-Up-Expire-Cache"], "false");
+
+        unpoly.expire_cache("/users/*").unwrap();
+        assert!(!unpoly.keeps_cache());
+        assert_eq!(
+            unpoly.get_headers().unwrap()["X-Up-Expire-Cache"],
+            "/users/*"
+        );
+    }
+
+    #[test]
+    fn test_set_cache() {
+        let mut unpoly = Unpoly::default();
+        unpoly.expire_cache("/users/*").unwrap();
+        unpoly.set_expire_cache("/tasks");
+        unpoly.set_evict_cache("*");
+        let headers = unpoly.get_headers().unwrap();
+        assert_eq!(headers["X-Up-Expire-Cache"], "/tasks");
+        assert_eq!(headers["X-Up-Evict-Cache"], "*");
+
+        unpoly.set_expire_cache("false");
+        assert!(unpoly.keeps_cache());
+        assert_eq!(unpoly.get_headers().unwrap()["X-Up-Expire-Cache"], "false");
+    }
+
+    #[test]
+    fn test_context_as() {
+        #[derive(Debug, Deserialize, Serialize, PartialEq)]
+        struct Game {
+            lives: u32,
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "3.0.0".parse().unwrap());
+        headers.insert(headers::CONTEXT, "{\"lives\": 42}".parse().unwrap());
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert_eq!(
+            unpoly.context_as::<Game>().unwrap(),
+            Some(Game { lives: 42 })
+        );
+        assert!(matches!(
+            unpoly.context_as::<Vec<u32>>(),
+            Err(Error::InvalidJson(_))
+        ));
+        assert_eq!(
+            unpoly.get_headers().unwrap()["Vary"],
+            "X-Up-Context,X-Up-Version".to_string()
+        );
+
+        unpoly.set_context(Game { lives: 41 }).unwrap();
+        assert_eq!(
+            unpoly.context_as::<Game>().unwrap(),
+            Some(Game { lives: 41 })
+        );
+
+        let mut unpoly = Unpoly::default();
+        assert_eq!(unpoly.context_as::<Game>().unwrap(), None);
+    }
+
+    #[test]
+    fn test_context_changes() {
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "3.0.0".parse().unwrap());
+        headers.insert(
+            headers::CONTEXT,
+            "{\"lives\": 42, \"level\": 1, \"name\": \"Bob\"}"
+                .parse()
+                .unwrap(),
+        );
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        unpoly.context_insert("lives", 41).unwrap();
+        unpoly.context_insert("bonus", true).unwrap();
+        unpoly.context_remove("level");
+        unpoly.context_remove("unknown");
+
+        assert_eq!(
+            unpoly.context(),
+            Some(&serde_json::json!({"lives": 41, "name": "Bob", "bonus": true}))
+        );
+        assert_eq!(
+            unpoly.get_headers().unwrap()["X-Up-Context"],
+            "{\"bonus\":true,\"level\":null,\"lives\":41}"
+        );
+
+        unpoly.context_insert("bonus", Value::Null).unwrap();
+        assert_eq!(
+            unpoly.get_headers().unwrap()["X-Up-Context"],
+            "{\"level\":null,\"lives\":41}"
+        );
+
+        unpoly
+            .set_context(serde_json::json!({"lives": 42, "name": "Alice"}))
+            .unwrap();
+        assert_eq!(
+            unpoly.context(),
+            Some(&serde_json::json!({"lives": 42, "name": "Alice"}))
+        );
+        assert_eq!(
+            unpoly.get_headers().unwrap()["X-Up-Context"],
+            "{\"level\":null,\"name\":\"Alice\"}"
+        );
+
+        unpoly.context_insert("name", "Zo\u{eb}").unwrap();
+        assert_eq!(
+            unpoly.get_headers().unwrap()["X-Up-Context"],
+            "{\"level\":null,\"name\":\"Zo\\u00eb\"}"
+        );
+
+        assert!(matches!(
+            unpoly.set_context(42),
+            Err(Error::ContextIsNotSerializableAsObject)
+        ));
+    }
+
+    #[test]
+    fn test_context_changes_before_set_success() {
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "3.0.0".parse().unwrap());
+        headers.insert(headers::CONTEXT, "{\"a\": 1}".parse().unwrap());
+        headers.insert(headers::FAIL_CONTEXT, "{\"b\": 2}".parse().unwrap());
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        unpoly.context_insert("x", 1).unwrap();
+        assert_eq!(unpoly.context(), Some(&serde_json::json!({"a": 1, "x": 1})));
+
+        unpoly.set_success(false);
+        assert_eq!(unpoly.context(), Some(&serde_json::json!({"b": 2, "x": 1})));
+        assert_eq!(unpoly.get_headers().unwrap()["X-Up-Context"], "{\"x\":1}");
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        unpoly.context_remove("a");
+        unpoly.set_success(false);
+        assert_eq!(unpoly.context(), Some(&serde_json::json!({"b": 2})));
+        assert!(unpoly.get_headers().unwrap().get("X-Up-Context").is_none());
+    }
+
+    #[test]
+    fn test_redirect() {
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "3.0.0".parse().unwrap());
+        headers.insert(headers::TARGET, ".users".parse().unwrap());
+        headers.insert(headers::MODE, "modal".parse().unwrap());
+        headers.insert(headers::CONTEXT, "{\"lives\": 42}".parse().unwrap());
+
+        let location = Unpoly::from_headers(&headers).redirect_location("/users?page=2");
+        assert_eq!(
+            location,
+            "/users?page=2&_up_version=3.0.0&_up_target=.users&_up_mode=modal\
+             &_up_context=%7B%22lives%22%3A42%7D"
+        );
+
+        // The redirect target does not receive the Unpoly headers
+        let (parts, _) = http::Request::builder()
+            .uri(location)
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let mut unpoly = Unpoly::from_request_parts(&parts);
+        assert!(unpoly.is_up());
+        assert_eq!(unpoly.target(), Some(".users"));
+        assert_eq!(*unpoly.mode(), LayerMode::MODAL);
+        assert_eq!(unpoly.context(), Some(&serde_json::json!({"lives": 42})));
+        assert_eq!(unpoly.location(), Some("/users?page=2"));
+        assert_eq!(unpoly.method(), Some("GET"));
+
+        let headers = unpoly.get_headers().unwrap();
+        assert_eq!(headers["X-Up-Location"], "/users?page=2");
+        assert_eq!(headers["X-Up-Method"], "GET");
+
+        assert_eq!(Unpoly::default().redirect_location("/users"), "/users");
+    }
+
+    #[test]
+    fn test_malformed_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::VERSION, "3.0.0".parse().unwrap());
+        headers.insert(headers::CONTEXT, "{lives: 42}".parse().unwrap());
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert_eq!(unpoly.context(), Some(&Value::Null));
+        assert!(matches!(
+            Unpoly::try_from_headers(&headers),
+            Err(Error::InvalidHeaderJson {
+                header: headers::CONTEXT,
+                ..
+            })
+        ));
+        let error = Unpoly::try_from_headers(&headers).unwrap_err();
+        assert!(std::error::Error::source(&error)
+            .is_some_and(|source| source.is::<serde_json::Error>()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::MODE, "sidebar".parse().unwrap());
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert_eq!(*unpoly.mode(), LayerMode::ROOT);
+        let error = Unpoly::try_from_headers(&headers).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Header X-Up-Mode does not contain a known layer mode: \"sidebar\""
+        );
+        assert!(std::error::Error::source(&error).is_none());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            headers::TARGET,
+            http::HeaderValue::from_bytes(b"#caf\xe9").unwrap(),
+        );
+
+        let mut unpoly = Unpoly::from_headers(&headers);
+        assert_eq!(unpoly.target(), None);
+        assert!(matches!(
+            Unpoly::try_from_headers(&headers),
+            Err(Error::InvalidHeaderEncoding {
+                header: headers::TARGET
+            })
+        ));
+    }
+}
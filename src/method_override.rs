@@ -0,0 +1,417 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    http::{header, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use http_body::Frame;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::OriginalMethod;
+
+/// The form field with which Unpoly wraps the method of a form submission
+///
+/// See <https://unpoly.com/up.protocol.config#config.methodParam>
+const METHOD_PARAM: &str = "_method";
+const METHOD_OVERRIDE_HEADER: &str = "X-HTTP-Method-Override";
+
+/// The default maximum size of a form body which is read to find the `_method` field
+const DEFAULT_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Layer which overrides the method of a `POST` request with the `_method` form field or the
+/// `X-HTTP-Method-Override` header
+///
+/// Unpoly wraps `PUT`, `PATCH` and `DELETE` form submissions as `POST` with a `_method` field, so they can be routed
+/// with `put(...)`, `patch(...)` and `delete(...)` when this layer is used. Only these methods are accepted as override.
+/// The original method is stored as `OriginalMethod` in the request extensions. The `Unpoly` extractor sees the
+/// overridden method and sends it as `X-Up-Method`.
+///
+/// Since the method must be overridden before routing, the layer must wrap the router instead of being added with
+/// `Router::layer`:
+///
+/// ```
+/// use axum::{extract::Request, routing::put, Router, ServiceExt};
+/// use tower_layer::Layer;
+///
+/// let router = Router::new().route("/users/{id}", put(|| async { "updated" }));
+/// let app = unpoly::MethodOverrideLayer::new().layer(router);
+/// # async {
+/// let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+/// axum::serve(listener, ServiceExt::<Request>::into_make_service(app)).await.unwrap();
+/// # };
+/// ```
+///
+/// The body of `application/x-www-form-urlencoded` requests is buffered to find the `_method` field; bodies larger
+/// than the limit (2 MiB by default) are rejected with `413 Payload Too Large`. Of `multipart/form-data` requests,
+/// like file uploads, only the start of the body up to the limit is read, after which the complete body is passed on
+/// unchanged; a `_method` field beyond the limit is not found.
+#[derive(Debug, Clone)]
+pub struct MethodOverrideLayer {
+    body_limit: usize,
+}
+
+impl MethodOverrideLayer {
+    pub fn new() -> Self {
+        MethodOverrideLayer {
+            body_limit: DEFAULT_BODY_LIMIT,
+        }
+    }
+
+    /// Set the maximum size of a form body which is read to find the `_method` field
+    pub fn body_limit(mut self, body_limit: usize) -> Self {
+        self.body_limit = body_limit;
+        self
+    }
+}
+
+impl Default for MethodOverrideLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Layer<S> for MethodOverrideLayer {
+    type Service = MethodOverride<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MethodOverride {
+            inner,
+            body_limit: self.body_limit,
+        }
+    }
+}
+
+/// Service which overrides the method of a request, see `MethodOverrideLayer`
+#[derive(Debug, Clone)]
+pub struct MethodOverride<S> {
+    inner: S,
+    body_limit: usize,
+}
+
+impl<S> Service<Request> for MethodOverride<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Take the service which is ready, leaving a clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let body_limit = self.body_limit;
+        Box::pin(async move {
+            match override_method(request, body_limit).await {
+                Ok(request) => inner.call(request).await,
+                Err(response) => Ok(response),
+            }
+        })
+    }
+}
+
+/// Override the method of the request, if it is a `POST` with an override
+async fn override_method(request: Request, body_limit: usize) -> Result<Request, Response> {
+    if request.method() != Method::POST {
+        return Ok(request);
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let content_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    let (method, body) = if let Some(method) = parts.headers.get(METHOD_OVERRIDE_HEADER) {
+        (method.to_str().ok().map(str::to_string), body)
+    } else if mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        let bytes = Limited::new(body, body_limit)
+            .collect()
+            .await
+            .map_err(|err| {
+                if err.is::<LengthLimitError>() {
+                    StatusCode::PAYLOAD_TOO_LARGE.into_response()
+                } else {
+                    StatusCode::BAD_REQUEST.into_response()
+                }
+            })?
+            .to_bytes();
+        (urlencoded_method(&bytes), Body::from(bytes))
+    } else if mime.eq_ignore_ascii_case("multipart/form-data") {
+        let (prefix, complete, body) = peek_body(body, body_limit)
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+        (multipart_method(&content_type, &prefix, complete), body)
+    } else {
+        (None, body)
+    };
+
+    if let Some(method) = method.and_then(|method| overridable_method(&method)) {
+        parts.extensions.insert(OriginalMethod(parts.method));
+        parts.method = method;
+    }
+    Ok(Request::from_parts(parts, body))
+}
+
+/// Read the start of a body, until at least `limit` bytes are read or the body is complete
+///
+/// Returns the bytes read, whether the body is complete, and a body which yields the full body again.
+async fn peek_body(mut body: Body, limit: usize) -> Result<(Vec<u8>, bool, Body), axum::Error> {
+    let mut frames = VecDeque::new();
+    let mut prefix = vec![];
+    let complete = loop {
+        if prefix.len() >= limit {
+            break false;
+        }
+        let Some(frame) = body.frame().await else {
+            break true;
+        };
+        let frame = frame?;
+        if let Some(data) = frame.data_ref() {
+            prefix.extend_from_slice(data);
+        }
+        frames.push_back(frame);
+    };
+    Ok((
+        prefix,
+        complete,
+        Body::new(PeekedBody { frames, rest: body }),
+    ))
+}
+
+/// A body of which the first frames have already been read
+struct PeekedBody {
+    frames: VecDeque<Frame<Bytes>>,
+    rest: Body,
+}
+
+impl http_body::Body for PeekedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.frames.pop_front() {
+            Some(frame) => Poll::Ready(Some(Ok(frame))),
+            None => Pin::new(&mut self.rest).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.frames.is_empty() && self.rest.is_end_stream()
+    }
+}
+
+/// Returns the method to override with; only `PUT`, `PATCH` and `DELETE` are allowed
+fn overridable_method(method: &str) -> Option<Method> {
+    let method = Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes()).ok()?;
+    [Method::PUT, Method::PATCH, Method::DELETE]
+        .contains(&method)
+        .then_some(method)
+}
+
+/// Find the `_method` field in an urlencoded form body
+fn urlencoded_method(body: &Bytes) -> Option<String> {
+    form_urlencoded::parse(body)
+        .find(|(key, _)| key == METHOD_PARAM)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Find the `_method` field in (the start of) a multipart form body
+///
+/// When the body is not complete, the last part may be cut off and is ignored.
+fn multipart_method(content_type: &str, body: &[u8], complete: bool) -> Option<String> {
+    let boundary = content_type.split(';').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"'))
+    })?;
+    let delimiter = format!("--{boundary}");
+    let body = String::from_utf8_lossy(body);
+    let body = if complete {
+        &body[..]
+    } else {
+        &body[..body.rfind(delimiter.as_str())?]
+    };
+    body.split(delimiter.as_str()).find_map(|part| {
+        let (headers, value) = part.split_once("\r\n\r\n")?;
+        let is_method_field = headers.lines().any(|line| {
+            let line = line.to_ascii_lowercase();
+            line.starts_with("content-disposition:")
+                && line.contains(&format!("name=\"{METHOD_PARAM}\""))
+        });
+        is_method_field.then(|| value.trim_end_matches("\r\n").to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::to_bytes,
+        routing::{delete, post, put},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::Unpoly;
+
+    fn app() -> MethodOverride<Router> {
+        let router = Router::new()
+            .route(
+                "/users/1",
+                put(|unpoly: Unpoly, request: Request| async move {
+                    let original = request.extensions().get::<OriginalMethod>().cloned();
+                    let body = format!(
+                        "updated, original {:?}, unpoly {:?} {:?}",
+                        original.map(|m| m.0),
+                        unpoly.original_request_method(),
+                        unpoly.request_method(),
+                    );
+                    (unpoly.get_headers().unwrap(), body)
+                })
+                .post(|| async { "posted" }),
+            )
+            .route("/users/2", delete(|| async { "deleted" }));
+        MethodOverrideLayer::new().layer(router)
+    }
+
+    async fn body(response: Response) -> String {
+        String::from_utf8(to_bytes(response.into_body(), 1024).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_urlencoded() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/users/1")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("X-Up-Version", "3.0.0")
+            .body(Body::from("name=Bob&_method=put"))
+            .unwrap();
+
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["X-Up-Method"], "PUT");
+        assert_eq!(
+            body(response).await,
+            "updated, original Some(POST), unpoly Some(POST) Some(PUT)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multipart() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/users/2")
+            .header("Content-Type", "multipart/form-data; boundary=XyZ")
+            .body(Body::from(
+                "--XyZ\r\nContent-Disposition: form-data; name=\"name\"\r\n\r\nBob\r\n\
+                 --XyZ\r\nContent-Disposition: form-data; name=\"_method\"\r\n\r\nDELETE\r\n\
+                 --XyZ--\r\n",
+            ))
+            .unwrap();
+
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(body(response).await, "deleted");
+    }
+
+    #[tokio::test]
+    async fn test_header() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/users/2")
+            .header("X-HTTP-Method-Override", "DELETE")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(body(response).await, "deleted");
+    }
+
+    #[tokio::test]
+    async fn test_no_override() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/users/1")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(Body::from("name=Bob&_method=GET"))
+            .unwrap();
+
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(body(response).await, "posted");
+    }
+
+    #[tokio::test]
+    async fn test_large_multipart() {
+        let upload = "x".repeat(64);
+        let multipart = |method: &str| {
+            format!(
+                "--XyZ\r\nContent-Disposition: form-data; name=\"_method\"\r\n\r\n{method}\r\n\
+                 --XyZ\r\nContent-Disposition: form-data; name=\"file\"\r\n\r\n{upload}\r\n\
+                 --XyZ--\r\n"
+            )
+        };
+        let app = || {
+            let router = Router::new().route(
+                "/upload",
+                post(|body: String| async move { format!("posted {}", body.len()) })
+                    .delete(|body: String| async move { format!("deleted {}", body.len()) }),
+            );
+            MethodOverrideLayer::new().body_limit(80).layer(router)
+        };
+        let request = |body: String| {
+            Request::builder()
+                .method("POST")
+                .uri("/upload")
+                .header("Content-Type", "multipart/form-data; boundary=XyZ")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let form = multipart("DELETE");
+        let response = app().oneshot(request(form.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, format!("deleted {}", form.len()));
+
+        // Without `_method`, an upload larger than the limit is passed on unchanged
+        let form = multipart("").replace("_method", "name");
+        let response = app().oneshot(request(form.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, format!("posted {}", form.len()));
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/users/1")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(Body::from("name=Bob&_method=put"))
+            .unwrap();
+
+        let response = MethodOverrideLayer::new()
+            .body_limit(4)
+            .layer(Router::new())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,657 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+#[cfg(feature = "fragment")]
+use axum::{
+    body::{to_bytes, Body},
+    extract::MatchedPath,
+    http::{header, HeaderValue},
+};
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::headers::merge_headers;
+use crate::Unpoly;
+
+/// The response header which names a missing target selector, see `UnpolyLayer::check_targets()`
+#[cfg(feature = "fragment")]
+const MISSING_TARGET_HEADER: &str = "X-Unpoly-Missing-Target";
+
+/// Shared handle to the Unpoly object of a request, managed by `UnpolyLayer`
+///
+/// Handlers and extractors can extract the handle and mutate the Unpoly object; the layer adds the resulting
+/// headers to the response. Don't hold the lock across an `.await`.
+///
+/// The `Unpoly` and `StrictUnpoly` extractors take a copy of the Unpoly object, which is written back to the handle
+/// when it is dropped. Changes made via the handle while such a copy is alive are overwritten.
+///
+/// ```
+/// async fn handler(unpoly: unpoly::UnpolyHandle) -> String {
+///     let mut unpoly = unpoly.lock();
+///     unpoly.set_title("My App");
+///     let target = unpoly.target();
+///     todo!("render content for target")
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct UnpolyHandle {
+    unpoly: Arc<Mutex<Unpoly>>,
+    /// The error of parsing the request strictly, which rejects the `StrictUnpoly` extractor
+    strict_error: Option<Arc<str>>,
+}
+
+impl UnpolyHandle {
+    pub fn new(unpoly: Unpoly) -> Self {
+        UnpolyHandle {
+            unpoly: Arc::new(Mutex::new(unpoly)),
+            strict_error: None,
+        }
+    }
+
+    /// Lock the Unpoly object for reading or mutating it
+    pub fn lock(&self) -> MutexGuard<'_, Unpoly> {
+        // A panicking handler does not leave the Unpoly object in an inconsistent state
+        self.unpoly
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns a copy of the Unpoly object, which is written back to the handle when it is dropped
+    pub(crate) fn extract(&self) -> Unpoly {
+        let mut unpoly = self.lock().clone();
+        unpoly.handle = Some(self.clone());
+        unpoly
+    }
+
+    /// Same as `extract()`, but fails when the request headers are malformed
+    pub(crate) fn extract_strict(&self) -> Result<Unpoly, &str> {
+        match &self.strict_error {
+            Some(err) => Err(err),
+            None => Ok(self.extract()),
+        }
+    }
+}
+
+impl Drop for Unpoly {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            *handle.lock() = std::mem::take(self);
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for UnpolyHandle
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<UnpolyHandle>().cloned().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "UnpolyHandle can only be extracted when UnpolyLayer is used",
+        ))
+    }
+}
+
+/// Layer which manages the Unpoly object of a request and adds its headers to the response
+///
+/// The request headers are parsed once into an `UnpolyHandle`, which is stored in the request extensions. The
+/// `Unpoly` and `StrictUnpoly` extractors are built from this handle and write their changes back to it.
+/// After the inner service responds, the Unpoly response headers (like `Vary`, `X-Up-Events` and `X-Up-Title`)
+/// are merged into the response, also for error responses. When the headers cannot be encoded, the response
+/// is replaced by a `500 Internal Server Error`.
+///
+/// ```
+/// use axum::{routing::get, Router};
+///
+/// let app: Router = Router::new()
+///     .route("/", get(|unpoly: unpoly::UnpolyHandle| async move {
+///         unpoly.lock().set_title("Home");
+///         "Home"
+///     }))
+///     .layer(unpoly::UnpolyLayer::new());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct UnpolyLayer {
+    #[cfg(feature = "fragment")]
+    extract_fragments: bool,
+    #[cfg(feature = "fragment")]
+    check_targets: bool,
+    #[cfg(feature = "fragment")]
+    extract_title: bool,
+}
+
+impl UnpolyLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send only the elements targeted by an Unpoly request from an HTML response, see `unpoly::extract_fragments()`
+    ///
+    /// The fail target is used for responses with an error status, and a target set by the handler (e.g. with
+    /// `Unpoly::set_target()` or an `X-Up-Target` response header) replaces the target of the request. The body of
+    /// HTML responses to Unpoly requests is buffered, so handlers can render the full page.
+    #[cfg(feature = "fragment")]
+    pub fn extract_fragments(mut self) -> Self {
+        self.extract_fragments = true;
+        self
+    }
+
+    /// Check that the HTML response to an Unpoly request contains the targeted elements, for use during development
+    ///
+    /// The target is checked before fragments are extracted, using the fail target for responses with an error
+    /// status and the target set by the handler, if any. For each missing selector, a warning naming the route and
+    /// the selector is logged with `tracing` and added to the response as `X-Unpoly-Missing-Target` header, instead of
+    /// the Unpoly client reporting a cryptic error.
+    ///
+    /// ```
+    /// let layer = unpoly::UnpolyLayer::new();
+    /// let layer = if cfg!(debug_assertions) { layer.check_targets() } else { layer };
+    /// ```
+    #[cfg(feature = "fragment")]
+    pub fn check_targets(mut self) -> Self {
+        self.check_targets = true;
+        self
+    }
+
+    /// Set `X-Up-Title` to the `<title>` of the HTML response to an Unpoly request
+    ///
+    /// This keeps the browser title up to date when a handler renders the full layout, but Unpoly only updates a
+    /// fragment. The title is not set when the handler called `Unpoly::set_title()` or the response already has an
+    /// `X-Up-Title` header, or when the target includes `head` or `html`, since Unpoly then updates the title itself.
+    #[cfg(feature = "fragment")]
+    pub fn extract_title(mut self) -> Self {
+        self.extract_title = true;
+        self
+    }
+}
+
+impl<S> Layer<S> for UnpolyLayer {
+    type Service = UnpolyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        UnpolyService {
+            inner,
+            #[cfg(feature = "fragment")]
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Service which manages the Unpoly object of a request, see `UnpolyLayer`
+#[derive(Debug, Clone)]
+pub struct UnpolyService<S> {
+    inner: S,
+    #[cfg(feature = "fragment")]
+    layer: UnpolyLayer,
+}
+
+impl<S> Service<Request> for UnpolyService<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Take the service which is ready, leaving a clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let (mut parts, body) = request.into_parts();
+        // Malformed headers are only rejected by the `StrictUnpoly` extractor
+        let handle = match Unpoly::try_from_request_parts(&parts) {
+            Ok(unpoly) => UnpolyHandle::new(unpoly),
+            Err(err) => UnpolyHandle {
+                strict_error: Some(err.to_string().into()),
+                ..UnpolyHandle::new(Unpoly::from_request_parts(&parts))
+            },
+        };
+        parts.extensions.insert(handle.clone());
+        #[cfg(feature = "fragment")]
+        let route = parts.extensions.get::<MatchedPath>().map_or_else(
+            || parts.uri.path().to_string(),
+            |path| path.as_str().to_string(),
+        );
+        let request = Request::from_parts(parts, body);
+
+        #[cfg(feature = "fragment")]
+        let layer = self.layer.clone();
+        Box::pin(async move {
+            let mut response = inner.call(request).await?;
+            #[cfg(feature = "fragment")]
+            {
+                response = transform_html_response(&layer, &handle, &route, response).await;
+            }
+            let headers = handle.lock().get_headers();
+            match headers {
+                Ok(headers) => merge_headers(response.headers_mut(), headers),
+                Err(err) => {
+                    response = (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Cannot encode Unpoly headers: {err}"),
+                    )
+                        .into_response()
+                }
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// Check and extract the targeted fragments and title of an HTML response to an Unpoly request, as configured by the
+/// layer
+#[cfg(feature = "fragment")]
+async fn transform_html_response(
+    layer: &UnpolyLayer,
+    handle: &UnpolyHandle,
+    route: &str,
+    response: Response,
+) -> Response {
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    if !(layer.extract_fragments || layer.check_targets || layer.extract_title) || !is_html {
+        return response;
+    }
+    let target = {
+        let mut unpoly = handle.lock();
+        if !unpoly.is_up() {
+            return response;
+        }
+        // A target which the handler sent itself replaces the target of the request
+        match response
+            .headers()
+            .get(crate::headers::TARGET)
+            .and_then(|target| target.to_str().ok())
+        {
+            Some(target) => Some(crate::Target::parse(target)),
+            None => unpoly.response_target(response.status().is_success()),
+        }
+    };
+    let Some(target) = target else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = to_bytes(body, usize::MAX).await else {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Cannot read response body",
+        )
+            .into_response();
+    };
+    let Ok(html) = std::str::from_utf8(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    if layer.check_targets {
+        for selector in crate::fragment::missing_selectors(html, &target) {
+            let message =
+                format!("Response of route {route} does not contain target selector {selector:?}");
+            tracing::warn!("{message}");
+            if let Ok(value) = HeaderValue::from_str(&message) {
+                parts.headers.append(MISSING_TARGET_HEADER, value);
+            }
+        }
+    }
+    if layer.extract_title
+        && !target.contains("head")
+        && !target.contains("html")
+        && !parts.headers.contains_key(crate::headers::TITLE)
+    {
+        let mut unpoly = handle.lock();
+        if unpoly.title().is_none() {
+            if let Some(title) = crate::fragment::title(html) {
+                unpoly.set_title(title);
+            }
+        }
+    }
+    if !layer.extract_fragments {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+    let fragments = crate::extract_fragments(html, &target);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(fragments))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::header, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::StrictUnpoly;
+
+    fn app() -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(|unpoly: UnpolyHandle| async move {
+                    let mut unpoly = unpoly.lock();
+                    unpoly.set_title("Home");
+                    unpoly.target();
+                    ([(header::VARY, "Accept-Encoding")], "Home")
+                }),
+            )
+            .route(
+                "/error",
+                get(|unpoly: UnpolyHandle| async move {
+                    unpoly
+                        .lock()
+                        .emit_event("error:shown", serde_json::json!({}))
+                        .unwrap();
+                    StatusCode::UNPROCESSABLE_ENTITY
+                }),
+            )
+            .route(
+                "/invalid",
+                get(|unpoly: UnpolyHandle| async move {
+                    unpoly.lock().set_location("/\n");
+                    "Invalid"
+                }),
+            )
+            .layer(UnpolyLayer::new())
+    }
+
+    fn request(uri: &str) -> Request {
+        Request::builder()
+            .uri(uri)
+            .header("X-Up-Version", "3.0.0")
+            .header("X-Up-Target", ".main")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unpoly_layer() {
+        let response = app().oneshot(request("/")).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-Up-Title"], "\"Home\"");
+        assert_eq!(
+            response.headers()["Vary"],
+            "Accept-Encoding,X-Up-Target,X-Up-Version"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unpoly_layer_error_response() {
+        let response = app().oneshot(request("/error")).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            response.headers()["X-Up-Events"],
+            "[{\"type\":\"error:shown\"}]"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unpoly_layer_invalid_headers() {
+        let response = app().oneshot(request("/invalid")).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_unpoly_layer_extractor() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|mut unpoly: Unpoly| async move {
+                    // The extractor sees the changes to the handle of the layer
+                    assert_eq!(unpoly.title(), Some("Users"));
+                    unpoly.target();
+                    unpoly
+                        .emit_event("user:shown", serde_json::json!({}))
+                        .unwrap();
+                    "Users"
+                })
+                .post(|unpoly: Unpoly| async move { (unpoly, "Created") }),
+            )
+            .route(
+                "/strict",
+                get(|unpoly: StrictUnpoly| async move {
+                    assert_eq!(unpoly.title(), Some("Users"));
+                    "Strict"
+                }),
+            )
+            .layer(axum::middleware::from_fn(
+                |request: Request, next: axum::middleware::Next| async move {
+                    request
+                        .extensions()
+                        .get::<UnpolyHandle>()
+                        .unwrap()
+                        .lock()
+                        .set_title("Users");
+                    next.run(request).await
+                },
+            ))
+            .layer(UnpolyLayer::new());
+
+        // Changes to the extracted Unpoly object are sent without returning it
+        let response = app.clone().oneshot(request("/")).await.unwrap();
+        assert_eq!(response.headers()["Vary"], "X-Up-Target,X-Up-Version");
+        assert_eq!(
+            response.headers()["X-Up-Events"],
+            "[{\"type\":\"user:shown\"}]"
+        );
+
+        let request_post = Request::builder()
+            .method("POST")
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request_post).await.unwrap();
+        assert_eq!(
+            response
+                .headers()
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .collect::<Vec<_>>(),
+            ["_up_method=POST; Path=/"]
+        );
+
+        let response = app.clone().oneshot(request("/strict")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-Up-Title"], "\"Users\"");
+
+        let mut request_invalid = request("/strict");
+        request_invalid
+            .headers_mut()
+            .insert("X-Up-Context", "{".parse().unwrap());
+        let response = app.oneshot(request_invalid).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[cfg(feature = "fragment")]
+    #[tokio::test]
+    async fn test_unpoly_layer_extract_fragments() {
+        use axum::response::Html;
+
+        const HTML: &str = "<html><head><title>Home</title></head>\
+            <body><nav>Menu</nav><div class=\"main\">Home</div><form>Invalid</form></body></html>";
+        let app = Router::new()
+            .route("/", get(|| async { Html(HTML) }))
+            .route(
+                "/error",
+                get(|| async { (StatusCode::UNPROCESSABLE_ENTITY, Html(HTML)) }),
+            )
+            .route(
+                "/retarget",
+                get(|mut unpoly: Unpoly| async move {
+                    unpoly.set_target("nav");
+                    (unpoly, Html(HTML))
+                }),
+            )
+            .route(
+                "/header",
+                get(|| async { ([(crate::headers::TARGET, "form")], Html(HTML)) }),
+            )
+            .layer(UnpolyLayer::new().extract_fragments());
+        let body = |response: Response| async {
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(bytes.to_vec()).unwrap()
+        };
+
+        let response = app.clone().oneshot(request("/")).await.unwrap();
+        assert_eq!(response.headers()["Vary"], "X-Up-Target,X-Up-Version");
+        assert_eq!(
+            body(response).await,
+            "<title>Home</title><div class=\"main\">Home</div>"
+        );
+
+        let mut request_error = request("/error");
+        request_error
+            .headers_mut()
+            .insert("X-Up-Fail-Target", "form".parse().unwrap());
+        let response = app.clone().oneshot(request_error).await.unwrap();
+        assert_eq!(
+            body(response).await,
+            "<title>Home</title><form>Invalid</form>"
+        );
+
+        let response = app.clone().oneshot(request("/retarget")).await.unwrap();
+        assert_eq!(response.headers()["X-Up-Target"], "nav");
+        assert_eq!(body(response).await, "<title>Home</title><nav>Menu</nav>");
+
+        let response = app.clone().oneshot(request("/header")).await.unwrap();
+        assert_eq!(
+            body(response).await,
+            "<title>Home</title><form>Invalid</form>"
+        );
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(body(response).await, HTML);
+    }
+
+    #[cfg(feature = "fragment")]
+    #[tokio::test]
+    async fn test_unpoly_layer_check_targets() {
+        use axum::response::Html;
+
+        const HTML: &str = "<html><body><div class=\"main\">User</div></body></html>";
+        let app = Router::new()
+            .route("/users/{id}", get(|| async { Html(HTML) }))
+            .route(
+                "/error",
+                get(|| async { (StatusCode::UNPROCESSABLE_ENTITY, Html(HTML)) }),
+            )
+            .route(
+                "/retarget",
+                get(|mut unpoly: Unpoly| async move {
+                    unpoly.set_target("#other");
+                    (unpoly, Html(HTML))
+                }),
+            )
+            .layer(UnpolyLayer::new().check_targets());
+
+        let response = app.clone().oneshot(request("/users/1")).await.unwrap();
+        assert!(response.headers().get(MISSING_TARGET_HEADER).is_none());
+
+        let mut request_user = request("/users/1");
+        request_user
+            .headers_mut()
+            .insert("X-Up-Target", "#user".parse().unwrap());
+        let response = app.clone().oneshot(request_user).await.unwrap();
+        assert_eq!(
+            response.headers()[MISSING_TARGET_HEADER],
+            "Response of route /users/{id} does not contain target selector \"#user\""
+        );
+
+        // The target sent by the handler is checked instead of the target of the request
+        let response = app.clone().oneshot(request("/retarget")).await.unwrap();
+        assert_eq!(
+            response
+                .headers()
+                .get_all(MISSING_TARGET_HEADER)
+                .iter()
+                .collect::<Vec<_>>(),
+            ["Response of route /retarget does not contain target selector \"#other\""]
+        );
+
+        let mut request = request("/error");
+        request
+            .headers_mut()
+            .insert("X-Up-Fail-Target", "form, #flash:maybe".parse().unwrap());
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(
+            response.headers()[MISSING_TARGET_HEADER],
+            "Response of route /error does not contain target selector \"form\""
+        );
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(bytes, HTML);
+    }
+
+    #[cfg(feature = "fragment")]
+    #[tokio::test]
+    async fn test_unpoly_layer_extract_title() {
+        use axum::response::Html;
+
+        const HTML: &str = "<html><head><title>Caf\u{e9}</title></head><body><div class=\"main\"></div></body></html>";
+        let app = Router::new()
+            .route("/", get(|| async { Html(HTML) }))
+            .route(
+                "/titled",
+                get(|unpoly: UnpolyHandle| async move {
+                    unpoly.lock().set_title("Handler");
+                    Html(HTML)
+                }),
+            )
+            .route(
+                "/extracted",
+                get(|mut unpoly: Unpoly| async move {
+                    unpoly.set_title("Extracted");
+                    (unpoly, Html(HTML))
+                }),
+            )
+            .route(
+                "/header",
+                get(|| async { ([(crate::headers::TITLE, "\"Header\"")], Html(HTML)) }),
+            )
+            .layer(UnpolyLayer::new().extract_title());
+
+        let response = app.clone().oneshot(request("/")).await.unwrap();
+        assert_eq!(response.headers()["X-Up-Title"], "\"Caf\\u00e9\"");
+
+        let response = app.clone().oneshot(request("/titled")).await.unwrap();
+        assert_eq!(response.headers()["X-Up-Title"], "\"Handler\"");
+
+        let response = app.clone().oneshot(request("/extracted")).await.unwrap();
+        assert_eq!(response.headers()["X-Up-Title"], "\"Extracted\"");
+
+        let response = app.clone().oneshot(request("/header")).await.unwrap();
+        assert_eq!(response.headers()["X-Up-Title"], "\"Header\"");
+
+        let mut request = request("/");
+        request
+            .headers_mut()
+            .insert("X-Up-Target", "head, .main".parse().unwrap());
+        let response = app.oneshot(request).await.unwrap();
+        assert!(response.headers().get("X-Up-Title").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unpoly_handle_without_layer() {
+        let app = Router::new().route("/", get(|_: UnpolyHandle| async { "Home" }));
+        let response = app.oneshot(request("/")).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
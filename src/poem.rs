@@ -0,0 +1,115 @@
+use http::{request::Parts, Request as HttpRequest};
+use poem::{http::StatusCode, FromRequest, IntoResponse, Request, RequestBody, Response, Result};
+
+use crate::headers::merge_headers;
+use crate::{StrictUnpoly, Unpoly, UpResponder};
+
+/// Copy the method, URI and headers of a poem request to `http::request::Parts`
+fn request_parts(request: &Request) -> Parts {
+    let (mut parts, _) = HttpRequest::new(()).into_parts();
+    parts.method = request.method().clone();
+    parts.uri = request.uri().clone();
+    parts.headers = request.headers().clone();
+    parts
+}
+
+impl<'a> FromRequest<'a> for Unpoly {
+    async fn from_request(request: &'a Request, _body: &mut RequestBody) -> Result<Self> {
+        Ok(Unpoly::from_request_parts(&request_parts(request)))
+    }
+}
+
+/// Rejects requests with malformed Unpoly headers with a `400 Bad Request`
+impl<'a> FromRequest<'a> for StrictUnpoly {
+    async fn from_request(request: &'a Request, _body: &mut RequestBody) -> Result<Self> {
+        Unpoly::try_from_request_parts(&request_parts(request))
+            .map(StrictUnpoly)
+            .map_err(|err| poem::Error::from_string(err.to_string(), StatusCode::BAD_REQUEST))
+    }
+}
+
+impl<R: IntoResponse> IntoResponse for UpResponder<R> {
+    fn into_response(self) -> Response {
+        match self.unpoly.get_headers() {
+            Ok(unpoly_headers) => {
+                let mut response = self.responder.into_response();
+                merge_headers(response.headers_mut(), unpoly_headers);
+                response
+            }
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Cannot encode Unpoly headers: {err}"),
+            )
+                .into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{get, handler, web::WithHeader, Endpoint, Route};
+
+    use super::*;
+
+    #[handler]
+    fn index(mut unpoly: Unpoly) -> UpResponder<WithHeader<&'static str>> {
+        unpoly.set_title("Home");
+        unpoly.target();
+        UpResponder::new(unpoly, "Home".with_header("Vary", "Accept-Encoding"))
+    }
+
+    #[handler]
+    fn strict(mut unpoly: StrictUnpoly) -> String {
+        format!("{:?}", unpoly.mode())
+    }
+
+    #[handler]
+    fn invalid(mut unpoly: Unpoly) -> UpResponder<&'static str> {
+        unpoly.set_location("/\n");
+        UpResponder::new(unpoly, "Invalid")
+    }
+
+    async fn get_response(uri: &str, headers: &[(&str, &str)]) -> Response {
+        let app = Route::new()
+            .at("/", get(index))
+            .at("/strict", get(strict))
+            .at("/invalid", get(invalid));
+        let request = headers
+            .iter()
+            .fold(Request::builder().uri_str(uri), |request, (name, value)| {
+                request.header(*name, *value)
+            })
+            .finish();
+        app.get_response(request).await
+    }
+
+    #[tokio::test]
+    async fn test_responder() {
+        let response =
+            get_response("/", &[("X-Up-Version", "3.0.0"), ("X-Up-Target", ".main")]).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-Up-Title"], "\"Home\"");
+        assert_eq!(
+            response.headers()["Vary"],
+            "Accept-Encoding,X-Up-Target,X-Up-Version"
+        );
+        assert_eq!(response.into_body().into_string().await.unwrap(), "Home");
+    }
+
+    #[tokio::test]
+    async fn test_strict_unpoly() {
+        let response = get_response("/strict", &[("X-Up-Mode", "modal")]).await;
+        assert_eq!(response.into_body().into_string().await.unwrap(), "MODAL");
+
+        let response = get_response("/strict", &[("X-Up-Context", "{")]).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_responder_invalid_headers() {
+        let response = get_response("/invalid", &[]).await;
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,130 @@
+use http::{HeaderMap, HeaderValue, Uri};
+
+use crate::headers;
+
+/// The request headers which are carried through a redirect as `_up_*` query parameters
+pub(crate) const HEADERS: [&str; 8] = [
+    headers::VERSION,
+    headers::TARGET,
+    headers::FAIL_TARGET,
+    headers::MODE,
+    headers::FAIL_MODE,
+    headers::ORIGIN_MODE,
+    headers::CONTEXT,
+    headers::FAIL_CONTEXT,
+];
+
+/// The query parameter for a header, like `_up_fail_target` for `X-Up-Fail-Target`
+fn param(header: &str) -> String {
+    let name = header.to_ascii_lowercase().replace('-', "_");
+    format!("_{}", name.trim_start_matches("x_"))
+}
+
+/// Append the headers as `_up_*` query parameters to the location, keeping a fragment at the end
+pub(crate) fn append_params<'a>(
+    location: &str,
+    values: impl IntoIterator<Item = (&'a str, String)>,
+) -> String {
+    let (location, fragment) = match location.find('#') {
+        Some(index) => location.split_at(index),
+        None => (location, ""),
+    };
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    for (header, value) in values {
+        query.append_pair(&param(header), &value);
+    }
+    let query = query.finish();
+    if query.is_empty() {
+        return format!("{location}{fragment}");
+    }
+    let separator = if location.contains('?') { '&' } else { '?' };
+    format!("{location}{separator}{query}{fragment}")
+}
+
+/// Returns true if the query parameter is one of the `_up_*` parameters
+fn is_up_param(pair: &str) -> bool {
+    pair.split('=')
+        .next()
+        .is_some_and(|key| key.starts_with("_up_"))
+}
+
+/// Check whether the request is the target of a redirect by an Unpoly request
+///
+/// A redirected request has the `_up_version` query parameter; other `_up_*` parameters alone are ignored.
+/// Returns the request headers completed with the headers from the `_up_*` query parameters, and the location of
+/// the request without these parameters.
+pub(crate) fn redirected_request(uri: &Uri, headers: &HeaderMap) -> Option<(HeaderMap, String)> {
+    let query = uri.query()?;
+    let version = param(headers::VERSION);
+    if !form_urlencoded::parse(query.as_bytes()).any(|(key, _)| key == version) {
+        return None;
+    }
+
+    let mut headers = headers.clone();
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        let Some(header) = HEADERS.into_iter().find(|header| param(header) == key) else {
+            continue;
+        };
+        // Headers which are passed through the redirect take precedence
+        if headers.contains_key(header) {
+            continue;
+        }
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(header, value);
+        }
+    }
+
+    let query = query
+        .split('&')
+        .filter(|pair| !is_up_param(pair))
+        .collect::<Vec<_>>()
+        .join("&");
+    let location = if query.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), query)
+    };
+    Some((headers, location))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_param() {
+        assert_eq!(param(headers::VERSION), "_up_version");
+        assert_eq!(param(headers::FAIL_TARGET), "_up_fail_target");
+    }
+
+    #[test]
+    fn test_append_params() {
+        assert_eq!(
+            append_params(
+                "/users?page=2#top",
+                [(headers::TARGET, ".users, #flash".to_string())]
+            ),
+            "/users?page=2&_up_target=.users%2C+%23flash#top"
+        );
+        assert_eq!(append_params("/users", []), "/users");
+    }
+
+    #[test]
+    fn test_redirected_request() {
+        let uri: Uri = "/users?_up_version=3.0.0&page=2&_up_target=.users%2C+%23flash"
+            .parse()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(headers::TARGET, ".main".parse().unwrap());
+
+        let (headers, location) = redirected_request(&uri, &headers).unwrap();
+        assert_eq!(location, "/users?page=2");
+        assert_eq!(headers[headers::VERSION], "3.0.0");
+        assert_eq!(headers[headers::TARGET], ".main");
+
+        assert!(redirected_request(&"/users?page=2".parse().unwrap(), &headers).is_none());
+        assert!(
+            redirected_request(&"/users?_up_target=.users".parse().unwrap(), &headers).is_none()
+        );
+    }
+}
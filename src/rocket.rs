@@ -0,0 +1,167 @@
+use std::convert::Infallible;
+use std::io::Cursor;
+
+use http::{
+    request::Parts, HeaderMap, HeaderName, HeaderValue, Method, Request as HttpRequest, Uri,
+};
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+    response::{self, Responder, Response},
+};
+
+use crate::headers;
+use crate::{Error, StrictUnpoly, Unpoly, UpResponder};
+
+/// Convert the method, URI and headers of a Rocket request to `http::request::Parts`
+fn request_parts(request: &Request<'_>) -> Parts {
+    let (mut parts, _) = HttpRequest::new(()).into_parts();
+    parts.method = Method::from_bytes(request.method().as_str().as_bytes()).unwrap_or_default();
+    parts.uri = request.uri().to_string().parse::<Uri>().unwrap_or_default();
+    let mut headers = HeaderMap::new();
+    for header in request.headers().iter() {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(header.name().as_str().as_bytes()),
+            HeaderValue::from_str(header.value()),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    parts.headers = headers;
+    parts
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Unpoly {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Unpoly::from_request_parts(&request_parts(request)))
+    }
+}
+
+/// Rejects requests with malformed Unpoly headers with a `400 Bad Request`
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for StrictUnpoly {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match Unpoly::try_from_request_parts(&request_parts(request)) {
+            Ok(unpoly) => Outcome::Success(StrictUnpoly(unpoly)),
+            Err(err) => Outcome::Error((Status::BadRequest, err)),
+        }
+    }
+}
+
+/// Merge the Unpoly headers into a Rocket response, like `headers::merge_headers`
+fn merge_headers(response: &mut Response<'_>, unpoly_headers: HeaderMap) {
+    for (name, value) in unpoly_headers.iter() {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        let name = name.as_str().to_string();
+        if name.eq_ignore_ascii_case(headers::VARY) {
+            let merged = headers::merge_vary(response.headers().get(&name).chain([value]));
+            response.set_raw_header(name, merged);
+        } else if name.eq_ignore_ascii_case(headers::SET_COOKIE) {
+            response.adjoin_raw_header(name, value.to_string());
+        } else {
+            response.set_raw_header(name, value.to_string());
+        }
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for UpResponder<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        match self.unpoly.get_headers() {
+            Ok(unpoly_headers) => {
+                let mut response = self.responder.respond_to(request)?;
+                merge_headers(&mut response, unpoly_headers);
+                Ok(response)
+            }
+            Err(err) => {
+                let body = format!("Cannot encode Unpoly headers: {err}");
+                Response::build()
+                    .status(Status::InternalServerError)
+                    .sized_body(body.len(), Cursor::new(body))
+                    .ok()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::{
+        get,
+        http::Header,
+        local::blocking::{Client, LocalRequest},
+        routes,
+    };
+
+    use super::*;
+
+    #[get("/")]
+    fn index(mut unpoly: Unpoly) -> UpResponder<(Status, &'static str)> {
+        unpoly.set_title("Home");
+        unpoly.target();
+        UpResponder::new(unpoly, (Status::Ok, "Home"))
+    }
+
+    #[get("/strict")]
+    fn strict(mut unpoly: StrictUnpoly) -> String {
+        format!("{:?}", unpoly.mode())
+    }
+
+    #[get("/invalid")]
+    fn invalid(mut unpoly: Unpoly) -> UpResponder<&'static str> {
+        unpoly.set_location("/\n");
+        UpResponder::new(unpoly, "Invalid")
+    }
+
+    fn client() -> Client {
+        Client::untracked(rocket::build().mount("/", routes![index, strict, invalid])).unwrap()
+    }
+
+    fn request<'c>(client: &'c Client, uri: &'static str) -> LocalRequest<'c> {
+        client
+            .get(uri)
+            .header(Header::new("X-Up-Version", "3.0.0"))
+            .header(Header::new("X-Up-Target", ".main"))
+            .header(Header::new("X-Up-Mode", "modal"))
+    }
+
+    #[test]
+    fn test_responder() {
+        let client = client();
+        let response = request(&client, "/").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("X-Up-Title"), Some("\"Home\""));
+        assert_eq!(
+            response.headers().get_one("Vary"),
+            Some("X-Up-Target,X-Up-Version")
+        );
+        assert_eq!(response.into_string().unwrap(), "Home");
+    }
+
+    #[test]
+    fn test_strict_unpoly() {
+        let client = client();
+        let response = request(&client, "/strict").dispatch();
+        assert_eq!(response.into_string().unwrap(), "MODAL");
+
+        let response = request(&client, "/strict")
+            .header(Header::new("X-Up-Context", "{"))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn test_responder_invalid_headers() {
+        let client = client();
+        let response = request(&client, "/invalid").dispatch();
+
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+}
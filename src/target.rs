@@ -0,0 +1,262 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// How the new content is placed in the targeted element
+///
+/// See <https://unpoly.com/targeting-fragments#appending-or-prepending-content>
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    #[default]
+    /// The targeted element is replaced
+    Swap,
+    /// The new content is prepended to the targeted element (`:before`)
+    Before,
+    /// The new content is appended to the targeted element (`:after`)
+    After,
+    /// Only the children of the targeted element are replaced (`:content`)
+    Content,
+}
+
+impl Placement {
+    fn suffix(&self) -> &'static str {
+        match self {
+            Placement::Swap => "",
+            Placement::Before => ":before",
+            Placement::After => ":after",
+            Placement::Content => ":content",
+        }
+    }
+}
+
+/// A single selector of a target, like `.content:after` or `#flash:maybe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetPart {
+    /// The selector without placement and `:maybe` pseudo classes
+    pub selector: String,
+    /// The placement of the new content
+    pub placement: Placement,
+    /// True if the selector is optional (`:maybe`), i.e. it will not fail when it cannot be matched
+    pub optional: bool,
+}
+
+impl TargetPart {
+    fn parse(part: &str) -> Self {
+        let mut selector = part.trim();
+        let mut placement = Placement::Swap;
+        let mut optional = false;
+        loop {
+            if let Some(rest) = selector.strip_suffix(":maybe") {
+                optional = true;
+                selector = rest;
+            } else if let Some((rest, found)) =
+                [Placement::Before, Placement::After, Placement::Content]
+                    .into_iter()
+                    .find_map(|p| selector.strip_suffix(p.suffix()).map(|rest| (rest, p)))
+            {
+                placement = found;
+                selector = rest;
+            } else {
+                break;
+            }
+        }
+        TargetPart {
+            selector: selector.trim().to_string(),
+            placement,
+            optional,
+        }
+    }
+
+    /// Returns true if the selector targets the whole page (`html` or `body`)
+    pub fn is_full_page(&self) -> bool {
+        self.selector == "html" || self.selector == "body"
+    }
+
+    /// Returns true if the selector targets the main element of the layer (`:main`)
+    pub fn is_main(&self) -> bool {
+        self.selector == ":main"
+    }
+
+    /// Returns true if the selector targets the whole layer (`:layer`)
+    pub fn is_layer(&self) -> bool {
+        self.selector == ":layer"
+    }
+
+    /// Returns true if nothing is targeted (`:none`)
+    pub fn is_none(&self) -> bool {
+        self.selector == ":none"
+    }
+}
+
+impl fmt::Display for TargetPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.selector, self.placement.suffix())?;
+        if self.optional {
+            write!(f, ":maybe")?;
+        }
+        Ok(())
+    }
+}
+
+/// A parsed `X-Up-Target`, a comma separated list of selectors
+///
+/// See <https://unpoly.com/targeting-fragments>
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Target {
+    parts: Vec<TargetPart>,
+}
+
+impl Target {
+    /// Parse a target like `.content, #flash:maybe`
+    ///
+    /// Commas within parentheses, brackets or quotes (like in `:is(.a, .b)`) do not separate selectors.
+    pub fn parse(target: &str) -> Self {
+        let mut parts = vec![];
+        let mut depth = 0u32;
+        let mut quote = None;
+        let mut start = 0;
+        for (i, c) in target.char_indices() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '"' | '\'') => quote = Some(c),
+                (None, '(' | '[') => depth += 1,
+                (None, ')' | ']') => depth = depth.saturating_sub(1),
+                (None, ',') if depth == 0 => {
+                    parts.push(&target[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        parts.push(&target[start..]);
+
+        Target {
+            parts: parts
+                .into_iter()
+                .filter(|part| !part.trim().is_empty())
+                .map(TargetPart::parse)
+                .collect(),
+        }
+    }
+
+    /// Returns the selectors of the target
+    pub fn parts(&self) -> &[TargetPart] {
+        &self.parts
+    }
+
+    /// Returns true if one of the parts has the given selector, ignoring placement and `:maybe`
+    ///
+    /// Note that this does not check whether the selector is part of a full page target; use
+    /// `is_full_page()` for that.
+    pub fn contains(&self, selector: &str) -> bool {
+        self.parts.iter().any(|part| part.selector == selector)
+    }
+
+    /// Returns true if nothing is targeted (`:none`)
+    pub fn is_none(&self) -> bool {
+        !self.parts.is_empty() && self.parts.iter().all(TargetPart::is_none)
+    }
+
+    /// Returns true if the whole page is targeted (`html` or `body`)
+    pub fn is_full_page(&self) -> bool {
+        self.parts.iter().any(TargetPart::is_full_page)
+    }
+}
+
+impl FromStr for Target {
+    type Err = std::convert::Infallible;
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        Ok(Target::parse(target))
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, part) in self.parts.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", part)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> IntoIterator for &'a Target {
+    type Item = &'a TargetPart;
+    type IntoIter = std::slice::Iter<'a, TargetPart>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.parts.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let target = Target::parse(".content, #flash:maybe, ul.tasks:after, #menu:content:maybe");
+
+        assert_eq!(
+            target.parts(),
+            &[
+                TargetPart {
+                    selector: ".content".to_string(),
+                    placement: Placement::Swap,
+                    optional: false
+                },
+                TargetPart {
+                    selector: "#flash".to_string(),
+                    placement: Placement::Swap,
+                    optional: true
+                },
+                TargetPart {
+                    selector: "ul.tasks".to_string(),
+                    placement: Placement::After,
+                    optional: false
+                },
+                TargetPart {
+                    selector: "#menu".to_string(),
+                    placement: Placement::Content,
+                    optional: true
+                },
+            ]
+        );
+        assert!(target.contains("#flash"));
+        assert!(!target.contains("#sidebar"));
+        assert!(!target.is_none());
+        assert!(!target.is_full_page());
+        assert_eq!(
+            target.to_string(),
+            ".content, #flash:maybe, ul.tasks:after, #menu:content:maybe"
+        );
+    }
+
+    #[test]
+    fn test_parse_nested_commas() {
+        let target = Target::parse(":is(.a, .b), [data-x=\"1,2\"]:before");
+
+        assert_eq!(target.parts().len(), 2);
+        assert_eq!(target.parts()[0].selector, ":is(.a, .b)");
+        assert_eq!(target.parts()[1].selector, "[data-x=\"1,2\"]");
+        assert_eq!(target.parts()[1].placement, Placement::Before);
+
+        // Unbalanced closing parentheses do not swallow the following selectors
+        let target = Target::parse("a), b, c");
+        assert_eq!(target.parts().len(), 3);
+        assert_eq!(target.parts()[2].selector, "c");
+    }
+
+    #[test]
+    fn test_special_targets() {
+        assert!(Target::parse(":none").is_none());
+        assert!(!Target::parse("").is_none());
+        assert!(Target::parse("body").is_full_page());
+        assert!(Target::parse("html, #flash").is_full_page());
+        assert!(Target::parse(":main").parts()[0].is_main());
+        assert!(Target::parse(":layer").parts()[0].is_layer());
+    }
+}
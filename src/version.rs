@@ -0,0 +1,86 @@
+use semver::Version;
+
+/// A part of the Unpoly server protocol which differs between Unpoly versions
+///
+/// See <https://unpoly.com/up.protocol>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolFeature {
+    /// Layer context in `X-Up-Context` (Unpoly 2)
+    Context,
+    /// `X-Up-Title` is a JSON encoded string instead of a plain string (Unpoly 3)
+    JsonTitle,
+    /// Cache control via `X-Up-Expire-Cache` and `X-Up-Evict-Cache` (Unpoly 3)
+    ExpireCache,
+    /// Reloads are conditional requests (`If-Modified-Since`) instead of `X-Up-Reload-From-Time` (Unpoly 3)
+    ConditionalReload,
+    /// The layer mode of the origin in `X-Up-Origin-Mode` (Unpoly 3)
+    OriginMode,
+    /// Multiple space separated field names in `X-Up-Validate` (Unpoly 3)
+    ValidateMultipleFields,
+}
+
+impl ProtocolFeature {
+    /// Returns the first Unpoly version supporting the feature
+    pub fn min_version(&self) -> Version {
+        match self {
+            ProtocolFeature::Context => Version::new(2, 0, 0),
+            ProtocolFeature::JsonTitle
+            | ProtocolFeature::ExpireCache
+            | ProtocolFeature::ConditionalReload
+            | ProtocolFeature::OriginMode
+            | ProtocolFeature::ValidateMultipleFields => Version::new(3, 0, 0),
+        }
+    }
+
+    /// Returns true if the given version supports the feature
+    ///
+    /// Pre-releases of the minimal version (like `3.0.0-rc1`) support the feature as well.
+    pub fn is_supported_by(&self, version: &Version) -> bool {
+        let min_version = self.min_version();
+        (version.major, version.minor, version.patch)
+            >= (min_version.major, min_version.minor, min_version.patch)
+    }
+}
+
+/// Parse an `X-Up-Version`, allowing missing minor and patch versions (like `2.7`)
+pub(crate) fn parse_version(version: &str) -> Option<Version> {
+    let version = version.trim();
+    Version::parse(version).ok().or_else(|| {
+        let mut parts = version
+            .split('.')
+            .map(|part| part.parse::<u64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        if parts.is_empty() || parts.len() > 3 {
+            return None;
+        }
+        parts.resize(3, 0);
+        Some(Version::new(parts[0], parts[1], parts[2]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("3.9.2"), Some(Version::new(3, 9, 2)));
+        assert_eq!(parse_version("2.7"), Some(Version::new(2, 7, 0)));
+        assert_eq!(parse_version("3"), Some(Version::new(3, 0, 0)));
+        assert_eq!(
+            parse_version("3.0.0-rc1"),
+            Some(Version::parse("3.0.0-rc1").unwrap())
+        );
+        assert_eq!(parse_version("three"), None);
+        assert_eq!(parse_version("1.2.3.4"), None);
+    }
+
+    #[test]
+    fn test_supported_by() {
+        assert!(ProtocolFeature::JsonTitle.is_supported_by(&Version::new(3, 0, 0)));
+        assert!(ProtocolFeature::JsonTitle.is_supported_by(&Version::parse("3.0.0-rc1").unwrap()));
+        assert!(!ProtocolFeature::JsonTitle.is_supported_by(&Version::new(2, 7, 2)));
+        assert!(ProtocolFeature::Context.is_supported_by(&Version::new(2, 7, 2)));
+        assert!(!ProtocolFeature::Context.is_supported_by(&Version::new(1, 0, 0)));
+    }
+}
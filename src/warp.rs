@@ -0,0 +1,162 @@
+use std::convert::Infallible;
+
+use http::{request::Parts, HeaderMap, Method, Request, Uri};
+use warp::{
+    filters::path::FullPath,
+    http::StatusCode,
+    reject::Reject,
+    reply::{Reply, Response},
+    Filter, Rejection,
+};
+
+use crate::headers::merge_headers;
+use crate::{Error, StrictUnpoly, Unpoly, UpResponder};
+
+impl Reject for Error {}
+
+/// Collect the method, URI and headers of the request as `http::request::Parts`
+fn request_parts() -> impl Filter<Extract = (Parts,), Error = Infallible> + Clone {
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+    warp::method()
+        .and(warp::path::full())
+        .and(query)
+        .and(warp::header::headers_cloned())
+        .map(
+            |method: Method, path: FullPath, query: String, headers: HeaderMap| {
+                let (mut parts, _) = Request::new(()).into_parts();
+                parts.method = method;
+                let uri = if query.is_empty() {
+                    path.as_str().to_string()
+                } else {
+                    format!("{}?{query}", path.as_str())
+                };
+                parts.uri = uri.parse::<Uri>().unwrap_or_default();
+                parts.headers = headers;
+                parts
+            },
+        )
+}
+
+/// Filter which extracts the Unpoly object of the request
+///
+/// ```
+/// use warp::Filter;
+///
+/// let route = warp::path!("users")
+///     .and(unpoly::unpoly_filter())
+///     .map(|mut unpoly: unpoly::Unpoly| {
+///         unpoly.set_title("Users");
+///         unpoly::UpResponder::new(unpoly, "Users")
+///     });
+/// ```
+pub fn unpoly_filter() -> impl Filter<Extract = (Unpoly,), Error = Infallible> + Clone {
+    request_parts().map(|parts: Parts| Unpoly::from_request_parts(&parts))
+}
+
+/// Filter which extracts the Unpoly object of the request and rejects requests with malformed Unpoly headers
+///
+/// The rejection contains the `unpoly::Error`, which can be handled with `Filter::recover`.
+pub fn strict_unpoly_filter() -> impl Filter<Extract = (StrictUnpoly,), Error = Rejection> + Clone {
+    request_parts().and_then(|parts: Parts| async move {
+        Unpoly::try_from_request_parts(&parts)
+            .map(StrictUnpoly)
+            .map_err(warp::reject::custom)
+    })
+}
+
+impl<R: Reply> Reply for UpResponder<R> {
+    fn into_response(self) -> Response {
+        match self.unpoly.get_headers() {
+            Ok(unpoly_headers) => {
+                let mut response = self.responder.into_response();
+                merge_headers(response.headers_mut(), unpoly_headers);
+                response
+            }
+            Err(err) => warp::reply::with_status(
+                format!("Cannot encode Unpoly headers: {err}"),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> warp::test::RequestBuilder {
+        warp::test::request()
+            .path("/users")
+            .header("X-Up-Version", "3.0.0")
+            .header("X-Up-Mode", "modal")
+            .header("X-Up-Target", ".main")
+    }
+
+    #[tokio::test]
+    async fn test_unpoly_filter() {
+        let mut unpoly = request().filter(&unpoly_filter()).await.unwrap();
+
+        assert!(unpoly.is_up());
+        assert_eq!(*unpoly.mode(), crate::LayerMode::MODAL);
+        assert_eq!(unpoly.target(), Some(".main"));
+    }
+
+    #[tokio::test]
+    async fn test_unpoly_filter_redirected() {
+        let mut unpoly = warp::test::request()
+            .path("/users?_up_version=3.0.0&_up_mode=modal&page=2")
+            .filter(&unpoly_filter())
+            .await
+            .unwrap();
+
+        assert!(unpoly.is_up());
+        assert_eq!(*unpoly.mode(), crate::LayerMode::MODAL);
+        let headers = unpoly.get_headers().unwrap();
+        assert_eq!(headers["X-Up-Location"], "/users?page=2");
+        assert_eq!(headers["X-Up-Method"], "GET");
+    }
+
+    #[tokio::test]
+    async fn test_strict_unpoly_filter() {
+        let rejection = request()
+            .header("X-Up-Context", "{")
+            .filter(&strict_unpoly_filter())
+            .await
+            .unwrap_err();
+
+        assert!(rejection.find::<Error>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reply() {
+        let route = unpoly_filter().map(|mut unpoly: Unpoly| {
+            unpoly.set_title("Home");
+            unpoly.target();
+            UpResponder::new(
+                unpoly,
+                warp::reply::with_header("Home", "Vary", "Accept-Encoding"),
+            )
+        });
+        let response = request().reply(&route).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-Up-Title"], "\"Home\"");
+        assert_eq!(
+            response.headers()["Vary"],
+            "Accept-Encoding,X-Up-Target,X-Up-Version"
+        );
+        assert_eq!(response.body(), "Home");
+    }
+
+    #[tokio::test]
+    async fn test_reply_invalid_headers() {
+        let route = unpoly_filter().map(|mut unpoly: Unpoly| {
+            unpoly.set_location("/\n");
+            UpResponder::new(unpoly, "Invalid")
+        });
+        let response = request().reply(&route).await;
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
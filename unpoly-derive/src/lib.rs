@@ -0,0 +1,69 @@
+//! Derive macros for the [unpoly](https://crates.io/crates/unpoly) crate
+//!
+//! Use them via the `derive` feature of the `unpoly` crate.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr};
+
+/// Derive `unpoly::UpEvent` for a struct with named fields
+///
+/// The event type is given with the `up_event` attribute:
+///
+/// ```ignore
+/// #[derive(Serialize, UpEvent)]
+/// #[up_event(name = "user:created")]
+/// struct UserCreated {
+///     id: u32,
+/// }
+/// ```
+#[proc_macro_derive(UpEvent, attributes(up_event))]
+pub fn derive_up_event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_up_event(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand_up_event(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    // Events are sent as JSON objects, so only structs with named fields are supported
+    match &input.data {
+        Data::Struct(data) if matches!(data.fields, Fields::Named(_)) => {}
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "UpEvent can only be derived for structs with named fields",
+            ))
+        }
+    }
+
+    let mut name: Option<LitStr> = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("up_event")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                name = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `name = \"...\"`"))
+            }
+        })?;
+    }
+    let name = name.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "missing event type, add #[up_event(name = \"...\")]",
+        )
+    })?;
+    if name.value().is_empty() {
+        return Err(syn::Error::new_spanned(name, "event type may not be empty"));
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::unpoly::UpEvent for #ident #ty_generics #where_clause {
+            const TYPE: &'static str = #name;
+        }
+    })
+}
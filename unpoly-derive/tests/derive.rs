@@ -0,0 +1,34 @@
+use serde::Serialize;
+use unpoly::{EventOptions, MatchingLayer, Unpoly, UpEvent};
+
+#[derive(Serialize, UpEvent)]
+#[up_event(name = "user:created")]
+struct UserCreated {
+    id: u32,
+}
+
+#[derive(Serialize, UpEvent)]
+#[up_event(name = "task:moved")]
+struct TaskMoved<T: Serialize> {
+    task: T,
+}
+
+#[test]
+fn test_derive_up_event() {
+    assert_eq!(UserCreated::TYPE, "user:created");
+    assert_eq!(TaskMoved::<u32>::TYPE, "task:moved");
+
+    let mut unpoly = Unpoly::default();
+    unpoly.emit(UserCreated { id: 152 }).unwrap();
+    unpoly
+        .emit_with(
+            TaskMoved { task: "write docs" },
+            EventOptions::new().layer(MatchingLayer::PARENT),
+        )
+        .unwrap();
+
+    assert_eq!(
+        unpoly.get_headers().unwrap()["X-Up-Events"],
+        "[{\"id\":152,\"type\":\"user:created\"},{\"layer\":\"parent\",\"task\":\"write docs\",\"type\":\"task:moved\"}]"
+    );
+}